@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Derive macro for `rudder_resource::PromiseAttributes`
+//!
+//! Use it through the `derive` feature of `rudder_resource`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr};
+
+/// Declared attribute, from a struct field
+struct Attribute {
+    field: syn::Ident,
+    name: String,
+    required: bool,
+    attribute_type: TokenStream2,
+}
+
+/// Implements `PromiseAttributes` for a struct with named fields
+///
+/// Each field needs an `#[attr(type = "...")]` annotation, containing an `AttributeType`
+/// variant, like `"AbsolutePath"` or `"ListOf(Box::new(Email))"`. Fields are optional unless
+/// marked `required`, and can be renamed with `rename = "..."`.
+#[proc_macro_derive(Promise, attributes(attr))]
+pub fn derive_promise(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "Promise can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "Promise can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut attributes = vec![];
+    for field in fields {
+        let ident = field.ident.clone().unwrap();
+        let mut attribute = Attribute {
+            name: ident.to_string(),
+            field: ident,
+            required: false,
+            attribute_type: TokenStream2::new(),
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("attr")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("required") {
+                    attribute.required = true;
+                } else if meta.path.is_ident("type") {
+                    let value: LitStr = meta.value()?.parse()?;
+                    attribute.attribute_type = value.value().parse().map_err(|_| {
+                        Error::new_spanned(&value, "Invalid attribute type expression")
+                    })?;
+                } else if meta.path.is_ident("rename") {
+                    let value: LitStr = meta.value()?.parse()?;
+                    attribute.name = value.value();
+                } else {
+                    return Err(meta.error("Unknown attr parameter"));
+                }
+                Ok(())
+            })?;
+        }
+        if attribute.attribute_type.is_empty() {
+            return Err(Error::new_spanned(
+                field,
+                "Missing #[attr(type = \"...\")] annotation",
+            ));
+        }
+        attributes.push(attribute);
+    }
+
+    let declaration = |required: bool| {
+        let items = attributes
+            .iter()
+            .filter(|a| a.required == required)
+            .map(|a| {
+                let name = &a.name;
+                let attribute_type = &a.attribute_type;
+                quote! {
+                    (#name.to_string(), {
+                        #[allow(unused_imports)]
+                        use ::rudder_resource::AttributeType::*;
+                        #attribute_type
+                    })
+                }
+            });
+        quote! { vec![#(#items),*] }
+    };
+    let required = declaration(true);
+    let optional = declaration(false);
+    let values = attributes.iter().map(|a| {
+        let field = &a.field;
+        let name = &a.name;
+        quote! {
+            #field: ::rudder_resource::__attribute_value(attributes, #name)?
+        }
+    });
+
+    let ident = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rudder_resource::PromiseAttributes for #ident #type_generics #where_clause {
+            fn required_attributes() -> Vec<(String, ::rudder_resource::AttributeType)> {
+                #required
+            }
+
+            fn optional_attributes() -> Vec<(String, ::rudder_resource::AttributeType)> {
+                #optional
+            }
+
+            fn from_attributes(attributes: &::rudder_resource::Attributes) -> Result<Self, String> {
+                Ok(Self {
+                    #(#values),*
+                })
+            }
+        }
+    })
+}
@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+use rudder_resource::{
+    attributes, name, version, AttributeType, Attributes, Promise, PromiseAttributes, PromiseType,
+    Value,
+};
+
+#[derive(Promise, Debug, PartialEq)]
+struct CloneArgs {
+    #[attr(required, type = "AbsolutePath")]
+    repo: String,
+    #[attr(type = "StringEnum(vec![\"master\".to_string(), \"main\".to_string()])")]
+    branch: Option<String>,
+    #[attr(rename = "depth", type = "Integer")]
+    clone_depth: Option<i64>,
+}
+
+struct Git {}
+
+impl PromiseType for Git {
+    name!("git_promise_module");
+    version!("0.0.1");
+    attributes!(CloneArgs);
+}
+
+#[test]
+fn it_declares_attributes() {
+    assert_eq!(
+        Git {}.required_attributes(),
+        vec![("repo".to_string(), AttributeType::AbsolutePath)]
+    );
+    assert_eq!(
+        Git {}.optional_attributes(),
+        vec![
+            (
+                "branch".to_string(),
+                AttributeType::StringEnum(vec!["master".to_string(), "main".to_string()])
+            ),
+            ("depth".to_string(), AttributeType::Integer),
+        ]
+    );
+}
+
+#[test]
+fn it_builds_typed_attributes() {
+    let mut attributes = Attributes::default();
+    attributes.insert("repo".to_string(), Value::from("/tmp/repo"));
+    attributes.insert("depth".to_string(), Value::from(1));
+    assert_eq!(
+        CloneArgs::from_attributes(&attributes),
+        Ok(CloneArgs {
+            repo: "/tmp/repo".to_string(),
+            branch: None,
+            clone_depth: Some(1),
+        })
+    );
+
+    attributes.remove("repo");
+    assert!(CloneArgs::from_attributes(&attributes)
+        .unwrap_err()
+        .starts_with("Attribute repo: "));
+}
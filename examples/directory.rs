@@ -4,25 +4,68 @@
 use std::{fs, path::Path};
 
 use rudder_resource::{
-    name, version, ApplyResult, AttributeType, CheckResult, Executor, PromiseType,
+    name, version, ApplyResult, AttributeEnum, AttributeType, Attributes, CheckResult, Executor,
+    ExecutorError, PromiseType, ValidateResult,
 };
-use serde_json::{Map, Value};
+use serde::Deserialize;
 
 struct Directory {}
 
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Present,
+    Absent,
+}
+
+impl AttributeEnum for State {
+    fn variants() -> &'static [&'static str] {
+        &["present", "absent"]
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "present" => Some(State::Present),
+            "absent" => Some(State::Absent),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DirectoryArgs {
+    state: State,
+}
+
+fn should_be_present(attributes: &Attributes) -> bool {
+    // Attributes were checked during validation
+    attributes
+        .deserialize::<DirectoryArgs>()
+        .map(|a| a.state == State::Present)
+        .unwrap_or(false)
+}
+
 impl PromiseType for Directory {
     name!("directory_module");
     version!("0.0.1");
 
     fn required_attributes(&self) -> Vec<(String, AttributeType)> {
-        vec![(
-            "state".to_string(),
-            AttributeType::StringEnum(vec!["present".to_string(), "absent".to_string()]),
-        )]
+        vec![("state".to_string(), AttributeType::enumeration::<State>())]
     }
 
-    fn check(&mut self, promiser: &str, attributes: &Map<String, Value>) -> CheckResult {
-        let should_be_present = attributes.get("state").unwrap().as_str().unwrap() == "present";
+    fn validate(&self, promiser: &str, attributes: &Attributes) -> ValidateResult {
+        let args = match attributes.deserialize::<DirectoryArgs>() {
+            Ok(args) => args,
+            Err(e) => return ValidateResult::Invalid(e),
+        };
+        if args.state == State::Present && Path::new(promiser).is_file() {
+            return ValidateResult::Invalid(format!("{} is an existing file", promiser));
+        }
+        ValidateResult::Valid
+    }
+
+    fn check(&mut self, promiser: &str, attributes: &Attributes) -> CheckResult {
+        let should_be_present = should_be_present(attributes);
 
         match (should_be_present, Path::new(&promiser).exists()) {
             (true, true) | (false, false) => CheckResult::Kept,
@@ -37,9 +80,19 @@ impl PromiseType for Directory {
         }
     }
 
-    fn apply(&mut self, promiser: &str, attributes: &Map<String, Value>) -> ApplyResult {
+    fn plan(&self, promiser: &str, attributes: &Attributes) -> Vec<String> {
+        let should_be_present = should_be_present(attributes);
+
+        match (should_be_present, Path::new(&promiser).exists()) {
+            (true, true) | (false, false) => vec![],
+            (true, false) => vec![format!("would create directory {}", promiser)],
+            (false, true) => vec![format!("would remove directory {}", promiser)],
+        }
+    }
+
+    fn apply(&mut self, promiser: &str, attributes: &Attributes) -> ApplyResult {
         let directory = Path::new(&promiser);
-        let should_be_present = attributes.get("state").unwrap().as_str().unwrap() == "present";
+        let should_be_present = should_be_present(attributes);
 
         match (should_be_present, directory.exists()) {
             (true, true) | (false, false) => ApplyResult::Kept,
@@ -59,8 +112,59 @@ impl PromiseType for Directory {
     }
 }
 
-fn main() -> Result<(), anyhow::Error> {
+fn main() -> Result<(), ExecutorError> {
     let directory_promise_type = Directory {};
     // Run the promise executor
     Executor::new().run(directory_promise_type)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Feed a full protocol exchange in dry-run mode and check each response
+    #[test]
+    fn it_serves_a_dry_run() {
+        let directory =
+            std::env::temp_dir().join(format!("rudder_directory_example_{}", std::process::id()));
+        let request = |operation: &str| {
+            format!(
+                r#"{{"operation":"{}","log_level":"info","promise_type":"directory","promiser":"{}","attributes":{{"state":"present"}},"filename":"/tmp/test.cf","line_number":42}}"#,
+                operation,
+                directory.display()
+            )
+        };
+        let input = format!(
+            "CFEngine 3.18.0 v1\n\n{}\n\n{}\n\n{}\n\n",
+            request("validate_promise"),
+            request("evaluate_promise"),
+            r#"{"operation":"terminate","log_level":"info"}"#
+        );
+
+        let output = Executor::new()
+            .warn_only(true)
+            .run_with_input(Directory {}, &input)
+            .unwrap();
+        let mut responses = output.split("\n\n").filter(|l| !l.is_empty());
+
+        assert_eq!(
+            responses.next(),
+            Some("directory_module 0.0.1 v1 json_based")
+        );
+        let results: Vec<Value> = responses
+            .map(|r| serde_json::from_str(r).unwrap())
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["operation"], "validate_promise");
+        assert_eq!(results[0]["result"], "valid");
+        assert_eq!(results[1]["operation"], "evaluate_promise");
+        assert_eq!(results[1]["result"], "not_kept");
+        assert_eq!(results[1]["attributes"]["state"], "present");
+        assert_eq!(results[2]["operation"], "terminate");
+        assert_eq!(results[2]["result"], "success");
+        // Nothing was changed
+        assert!(!directory.exists());
+    }
+}
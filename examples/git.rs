@@ -4,9 +4,9 @@
 use std::{path::Path, process::Command};
 
 use rudder_resource::{
-    info, name, version, ApplyResult, AttributeType, CheckResult, Executor, PromiseType,
+    info, name, version, ApplyResult, AttributeType, Attributes, CheckResult, Executor,
+    ExecutorError, PromiseType, ValidateResult,
 };
-use serde_json::{Map, Value};
 
 struct Git {}
 
@@ -19,7 +19,15 @@ impl PromiseType for Git {
         vec![("repo".to_string(), AttributeType::AbsolutePath)]
     }
 
-    fn check(&mut self, promiser: &str, _attributes: &Map<String, Value>) -> CheckResult {
+    fn validate_promiser(&self, promiser: &str) -> ValidateResult {
+        if Path::new(promiser).is_absolute() {
+            ValidateResult::Valid
+        } else {
+            ValidateResult::Invalid(format!("{} is not an absolute path", promiser))
+        }
+    }
+
+    fn check(&mut self, promiser: &str, _attributes: &Attributes) -> CheckResult {
         if Path::new(&promiser).exists() {
             CheckResult::Kept
         } else {
@@ -27,10 +35,13 @@ impl PromiseType for Git {
         }
     }
 
-    fn apply(&mut self, promiser: &str, attributes: &Map<String, Value>) -> ApplyResult {
+    fn apply(&mut self, promiser: &str, attributes: &Attributes) -> ApplyResult {
         let folder = Path::new(&promiser);
         // we have checked validity
-        let url = attributes.get("repo").unwrap().as_str().unwrap();
+        let url = match attributes.get_str("repo") {
+            Some(u) => u,
+            None => return ApplyResult::Error("Missing repo attribute".to_string()),
+        };
         // let key1 = attributes
         //     .get("dat")
         //     .unwrap()
@@ -51,7 +62,7 @@ impl PromiseType for Git {
 
         info!("Cloning '{}' -> '{}'...", url, folder.display());
 
-        match Command::new("git").args(&["clone", url, promiser]).output() {
+        match Command::new("git").args(["clone", url, promiser]).output() {
             Err(e) => ApplyResult::NotKept(e.to_string()),
             Ok(_) => {
                 if folder.exists() {
@@ -69,7 +80,7 @@ impl PromiseType for Git {
     }
 }
 
-fn main() -> Result<(), anyhow::Error> {
+fn main() -> Result<(), ExecutorError> {
     let git_promise_type = Git {};
     // Run the promise executor
     Executor::new().run(git_promise_type)
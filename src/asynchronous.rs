@@ -0,0 +1,441 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Async variant of the promise type interface
+//!
+//! The protocol is sequential, so the executor simply waits for each
+//! method's future to complete before reading the next request.
+//!
+//! With the `tokio` feature, futures run on a tokio current-thread runtime, with IO and
+//! timers enabled. Otherwise they are polled on the calling thread without any runtime:
+//! futures needing runtime services only work if a runtime runs in background threads and
+//! its context is entered when calling `Executor::run_async`.
+
+use std::future::Future;
+#[cfg(not(feature = "tokio"))]
+use std::{
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use serde_json::Value;
+
+use crate::{
+    ActionPolicy, ApplyResult, AttributeType, Attributes, Capabilities, CheckResult, InitSettings,
+    Platform, Progress, PromiseContext, PromiseType, ProtocolResult, ResourceKind, ValidateResult,
+};
+
+/// CFEngine promise type with async methods
+///
+/// Mirrors `PromiseType`, see its documentation for each method.
+// Futures are awaited in the calling thread, so they don't need to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait AsyncPromiseType {
+    fn name(&self) -> &'static str;
+    fn version(&self) -> &'static str;
+
+    fn kind(&self) -> ResourceKind {
+        ResourceKind::State
+    }
+
+    fn default_action_policy(&self) -> ActionPolicy {
+        ActionPolicy::Fix
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    fn supported_platforms(&self) -> Vec<Platform> {
+        vec![]
+    }
+
+    fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+        vec![]
+    }
+
+    fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+        vec![]
+    }
+
+    fn exclusive_groups(&self) -> Vec<Vec<String>> {
+        vec![]
+    }
+
+    fn required_groups(&self) -> Vec<Vec<String>> {
+        vec![]
+    }
+
+    fn conditional_requirements(&self) -> Vec<(String, Value, String)> {
+        vec![]
+    }
+
+    fn secret_attributes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn deprecated_attributes(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    fn attribute_aliases(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    fn attribute_defaults(&self) -> Vec<(String, Value)> {
+        vec![]
+    }
+
+    async fn init(&mut self) -> ProtocolResult {
+        ProtocolResult::Success
+    }
+
+    async fn init_with_settings(&mut self, _settings: &InitSettings) -> ProtocolResult {
+        self.init().await
+    }
+
+    async fn set_context(&mut self, _context: &PromiseContext) {}
+
+    async fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+        vec![promiser.to_string()]
+    }
+
+    async fn validate_promiser(&self, _promiser: &str) -> ValidateResult {
+        ValidateResult::Valid
+    }
+
+    async fn validate(&self, _promiser: &str, _attributes: &Attributes) -> ValidateResult {
+        ValidateResult::Valid
+    }
+
+    async fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+        CheckResult::AlwaysApply
+    }
+
+    async fn plan(&self, _promiser: &str, _attributes: &Attributes) -> Vec<String> {
+        vec![]
+    }
+
+    async fn apply(&mut self, _promiser: &str, _attributes: &Attributes) -> ApplyResult {
+        ApplyResult::AuditOnly
+    }
+
+    async fn apply_with_progress(
+        &mut self,
+        promiser: &str,
+        attributes: &Attributes,
+        _progress: &mut Progress<'_>,
+    ) -> ApplyResult {
+        self.apply(promiser, attributes).await
+    }
+
+    async fn apply_batch(
+        &mut self,
+        promises: &[(String, Attributes)],
+        progress: &mut Progress<'_>,
+    ) -> Vec<ApplyResult> {
+        let mut results = Vec::with_capacity(promises.len());
+        for (promiser, attributes) in promises {
+            results.push(
+                self.apply_with_progress(promiser, attributes, progress)
+                    .await,
+            );
+        }
+        results
+    }
+
+    async fn rollback(&mut self, _promiser: &str, _attributes: &Attributes) -> ProtocolResult {
+        ProtocolResult::Success
+    }
+
+    async fn terminate(&mut self) -> ProtocolResult {
+        ProtocolResult::Success
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+struct ThreadWaker(Thread);
+
+#[cfg(not(feature = "tokio"))]
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls futures on the calling thread, parking it while they are pending
+#[cfg(not(feature = "tokio"))]
+struct Runtime;
+
+#[cfg(not(feature = "tokio"))]
+impl Runtime {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    /// Run a future to completion on the current thread
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+struct Runtime(tokio::runtime::Runtime);
+
+#[cfg(feature = "tokio")]
+impl Runtime {
+    fn new() -> std::io::Result<Self> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map(Self)
+    }
+
+    /// Run a future to completion on the current thread, inside the runtime
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.0.block_on(future)
+    }
+}
+
+/// Exposes an async promise type as a blocking one
+pub(crate) struct Blocking<T> {
+    promise: T,
+    runtime: Runtime,
+}
+
+impl<T> Blocking<T> {
+    /// Panics if the runtime cannot be started
+    pub(crate) fn new(promise: T) -> Self {
+        Self {
+            promise,
+            runtime: Runtime::new().expect("could not start the async runtime"),
+        }
+    }
+}
+
+impl<T: AsyncPromiseType> PromiseType for Blocking<T> {
+    fn name(&self) -> &'static str {
+        self.promise.name()
+    }
+
+    fn version(&self) -> &'static str {
+        self.promise.version()
+    }
+
+    fn kind(&self) -> ResourceKind {
+        self.promise.kind()
+    }
+
+    fn default_action_policy(&self) -> ActionPolicy {
+        self.promise.default_action_policy()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.promise.capabilities()
+    }
+
+    fn supported_platforms(&self) -> Vec<Platform> {
+        self.promise.supported_platforms()
+    }
+
+    fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.promise.required_attributes()
+    }
+
+    fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.promise.optional_attributes()
+    }
+
+    fn exclusive_groups(&self) -> Vec<Vec<String>> {
+        self.promise.exclusive_groups()
+    }
+
+    fn required_groups(&self) -> Vec<Vec<String>> {
+        self.promise.required_groups()
+    }
+
+    fn conditional_requirements(&self) -> Vec<(String, Value, String)> {
+        self.promise.conditional_requirements()
+    }
+
+    fn secret_attributes(&self) -> Vec<String> {
+        self.promise.secret_attributes()
+    }
+
+    fn deprecated_attributes(&self) -> Vec<(String, String)> {
+        self.promise.deprecated_attributes()
+    }
+
+    fn attribute_aliases(&self) -> Vec<(String, String)> {
+        self.promise.attribute_aliases()
+    }
+
+    fn attribute_defaults(&self) -> Vec<(String, Value)> {
+        self.promise.attribute_defaults()
+    }
+
+    fn init(&mut self) -> ProtocolResult {
+        self.runtime.block_on(self.promise.init())
+    }
+
+    fn init_with_settings(&mut self, settings: &InitSettings) -> ProtocolResult {
+        self.runtime
+            .block_on(self.promise.init_with_settings(settings))
+    }
+
+    fn set_context(&mut self, context: &PromiseContext) {
+        self.runtime.block_on(self.promise.set_context(context))
+    }
+
+    fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+        self.runtime
+            .block_on(self.promise.expand_promiser(promiser))
+    }
+
+    fn validate_promiser(&self, promiser: &str) -> ValidateResult {
+        self.runtime
+            .block_on(self.promise.validate_promiser(promiser))
+    }
+
+    fn validate(&self, promiser: &str, attributes: &Attributes) -> ValidateResult {
+        self.runtime
+            .block_on(self.promise.validate(promiser, attributes))
+    }
+
+    fn check(&mut self, promiser: &str, attributes: &Attributes) -> CheckResult {
+        self.runtime
+            .block_on(self.promise.check(promiser, attributes))
+    }
+
+    fn plan(&self, promiser: &str, attributes: &Attributes) -> Vec<String> {
+        self.runtime
+            .block_on(self.promise.plan(promiser, attributes))
+    }
+
+    fn apply(&mut self, promiser: &str, attributes: &Attributes) -> ApplyResult {
+        self.runtime
+            .block_on(self.promise.apply(promiser, attributes))
+    }
+
+    fn apply_with_progress(
+        &mut self,
+        promiser: &str,
+        attributes: &Attributes,
+        progress: &mut Progress<'_>,
+    ) -> ApplyResult {
+        self.runtime.block_on(
+            self.promise
+                .apply_with_progress(promiser, attributes, progress),
+        )
+    }
+
+    fn apply_batch(
+        &mut self,
+        promises: &[(String, Attributes)],
+        progress: &mut Progress<'_>,
+    ) -> Vec<ApplyResult> {
+        self.runtime
+            .block_on(self.promise.apply_batch(promises, progress))
+    }
+
+    fn rollback(&mut self, promiser: &str, attributes: &Attributes) -> ProtocolResult {
+        self.runtime
+            .block_on(self.promise.rollback(promiser, attributes))
+    }
+
+    fn terminate(&mut self) -> ProtocolResult {
+        self.runtime.block_on(self.promise.terminate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        thread,
+    };
+
+    use super::*;
+
+    /// Becomes ready once woken up from another thread
+    struct Remote {
+        done: Arc<AtomicBool>,
+        started: bool,
+    }
+
+    impl Future for Remote {
+        type Output = u8;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+            if self.done.load(Ordering::SeqCst) {
+                return Poll::Ready(42);
+            }
+            if !self.started {
+                self.started = true;
+                let done = self.done.clone();
+                let waker = cx.waker().clone();
+                thread::spawn(move || {
+                    done.store(true, Ordering::SeqCst);
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn it_waits_for_pending_futures() {
+        let future = Remote {
+            done: Arc::new(AtomicBool::new(false)),
+            started: false,
+        };
+        assert_eq!(Runtime::new().unwrap().block_on(future), 42);
+    }
+
+    #[cfg(feature = "tokio")]
+    struct Sleeping {}
+
+    #[cfg(feature = "tokio")]
+    impl AsyncPromiseType for Sleeping {
+        fn name(&self) -> &'static str {
+            "test_promise_module"
+        }
+
+        fn version(&self) -> &'static str {
+            "0.0.1"
+        }
+
+        async fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            // Needs the timer of a running tokio runtime
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            CheckResult::Kept
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn it_runs_futures_on_tokio() {
+        let input = crate::testing::TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let output = crate::Executor::new()
+            .run_async_with_input(Sleeping {}, &input)
+            .unwrap();
+        assert!(output.contains(r#""result":"kept""#));
+    }
+}
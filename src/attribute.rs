@@ -1,13 +1,34 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2021 Normation SAS
 
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Deref, DerefMut},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
 
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use semver::{Version, VersionReq};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    cron::CronSchedule,
+    glob::Glob,
+    iso, schema,
+    timezone::{self, TimeZone},
+};
 
 /// First-level type of attributes
 ///
 /// Allows providing typing information
+///
+/// Most types only check the value itself. `ExistingFile`, `ExistingDir`, `ExistingPath`
+/// and `Timezone` access the file system during validation, and `ExistingUsername`,
+/// `ExistingGroupname` and `ExistingServiceName` query the system databases.
 #[derive(Debug, PartialEq, Clone)]
 pub enum AttributeType {
     Bool,
@@ -19,14 +40,266 @@ pub enum AttributeType {
     /// Can only be one of the given variant
     StringEnum(Vec<String>),
     AbsolutePath,
+    /// Octal permission mode, as a string of 3 or 4 octal digits ("0644")
+    /// or an integer written the same way (644)
+    FileMode,
+    /// Portable user name: 1 to 32 chars among `[A-Za-z0-9._-]`,
+    /// not starting with a `-`, and optionally not starting with a digit
+    Username {
+        leading_digit: bool,
+    },
+    /// Valid user name that exists on the system
+    ///
+    /// Only checks the name validity on platforms without a passwd database.
+    ExistingUsername,
+    /// Portable group name, following the same rules as `Username`
+    Groupname {
+        leading_digit: bool,
+    },
+    /// Valid group name that exists on the system
+    ///
+    /// Only checks the name validity on platforms without a group database.
+    ExistingGroupname,
+    /// Host name following RFC 1123, with an optional trailing dot
+    Hostname,
+    /// Fully qualified host name, with at least two labels and a non-numeric top-level domain
+    Fqdn,
+    /// Size in bytes, as a non-negative integer or a string with an optional unit
+    ///
+    /// SI units (`kB`, `MB`, `GB`, `TB`) are powers of 1000, and IEC units
+    /// (`KiB`, `MiB`, `GiB`, `TiB`) powers of 1024. `KB` is rejected as ambiguous.
+    Bytes,
+    /// UUID in canonical 8-4-4-4-12 hexadecimal form
+    ///
+    /// Braced (`{...}`) and URN (`urn:uuid:...`) forms are also accepted with `alternate_forms`.
+    Uuid {
+        alternate_forms: bool,
+    },
+    /// RFC 3339 timestamp, like `2021-06-01T12:30:00.5+02:00`
+    ///
+    /// The time zone offset is mandatory, fractional seconds and leap seconds are accepted.
+    DateTime,
+    /// Either `null` or a value of the inner type
+    Nullable(Box<AttributeType>),
+    /// Any of the given types
+    OneOf(Vec<AttributeType>),
+    /// Integer, or string containing a decimal, hexadecimal (`0x`) or octal (`0o`) integer
+    RadixInteger,
+    /// List of values of the given type
+    ListOf(Box<AttributeType>),
+    /// Email address, with a lenient check on the local part
+    /// and a domain containing at least one dot
+    Email,
+    /// Semantic version, like `1.2.3-beta.1`
+    Semver,
+    /// Semantic version requirement in Cargo syntax, like `>=1.2, <2`
+    SemverReq,
+    /// String with a number of characters (Unicode scalar values, not bytes)
+    /// between `min` and `max`, inclusive
+    StringLength {
+        min: usize,
+        max: usize,
+    },
+    /// Non-empty string, optionally also rejecting whitespace-only strings
+    NonEmptyString {
+        reject_whitespace: bool,
+    },
+    /// Absolute path to an existing regular file, following symlinks
+    ExistingFile,
+    /// Absolute path to an existing directory, following symlinks
+    ExistingDir,
+    /// Absolute path to anything existing, following symlinks
+    ExistingPath,
+    /// Non-empty relative path, optionally rejecting `..` components
+    RelativePath {
+        reject_parent: bool,
+    },
+    /// Any value matching the given JSON Schema
+    ///
+    /// Only a structural subset of JSON Schema is supported: `type`, `enum`, `const`,
+    /// `properties`, `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+    /// `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`,
+    /// `allOf`, `anyOf`, boolean schemas and annotations. Schemas using other keywords,
+    /// like `pattern` or `$ref`, reject every value.
+    JsonSchema(Value),
+    /// MAC address, as 6 hexadecimal octets separated by colons or hyphens,
+    /// or in Cisco dotted form (`001a.2b3c.4d5e`)
+    ///
+    /// Multicast and broadcast addresses are rejected with `unicast_only`.
+    MacAddress {
+        unicast_only: bool,
+    },
+    /// Cron schedule in crontab(5) syntax, with 5 fields, or 6 with a leading seconds
+    /// field when `seconds` is set
+    ///
+    /// Names (`jan`, `mon`), ranges, lists and steps are accepted, as well as the
+    /// Vixie-style `@yearly`, `@annually`, `@monthly`, `@weekly`, `@daily`, `@midnight`
+    /// and `@hourly` macros. `@reboot` is rejected as it is not a schedule.
+    Cron {
+        seconds: bool,
+    },
+    /// IANA time zone name, like `Europe/Paris`, existing in the system tz database
+    ///
+    /// Backward-compatibility aliases (like `US/Eastern`) are rejected with `reject_aliases`.
+    Timezone {
+        reject_aliases: bool,
+    },
+    /// Base64-encoded data, with the standard alphabet and padding, or the URL-safe alphabet
+    /// with optional padding when `url_safe` is set
+    ///
+    /// The decoded size is checked against `min_bytes` and `max_bytes`. Whitespace, like
+    /// line breaks in PEM-like blobs, is only accepted with `lenient`.
+    Base64 {
+        url_safe: bool,
+        lenient: bool,
+        min_bytes: usize,
+        max_bytes: Option<usize>,
+    },
+    /// Hex-encoded bytes, with a non-zero even number of digits and an optional `0x` prefix
+    ///
+    /// The decoded size has to be `bytes` when set, like 32 for a SHA-256 fingerprint.
+    Hex {
+        bytes: Option<usize>,
+    },
+    /// Valid glob pattern, see `Glob` for the supported syntax
+    Glob,
+    /// String matching any of the given glob patterns
+    ///
+    /// Invalid patterns never match.
+    MatchingGlobs(Vec<String>),
+    /// String containing JSON, like data flattened by the policy
+    ///
+    /// The decoded value is also checked against the inner type when set,
+    /// like `JsonSchema` or `Data`.
+    JsonString(Option<Box<AttributeType>>),
+    /// Variant of a Rust enum, built with `AttributeType::enumeration`
+    Enum(EnumType),
+    /// Absolute path staying under the given base once normalized
+    ///
+    /// Normalization is lexical: `.` components and duplicate separators are removed, and
+    /// `..` components remove the previous one. Symlinks are not resolved.
+    AbsolutePathUnder(PathBuf),
+    /// Boolean, as a JSON boolean or one of the usual policy spellings
+    ///
+    /// Accepts `true`/`false`, `yes`/`no`, `on`/`off` and `1`/`0`, in any case, as strings,
+    /// and the `1` and `0` integers. Anything else, like `y` or `2`, is rejected.
+    Boolish,
+    /// ISO 3166-1 country code, like `FR`, in any case
+    ///
+    /// Three-letter codes, like `FRA`, are expected with `alpha3`. User-assigned codes,
+    /// like `XK` or `ZZ`, are rejected unless `user_assigned` is set.
+    CountryCode {
+        alpha3: bool,
+        user_assigned: bool,
+    },
+    /// ISO 639 language code, like `fr`, in any case
+    ///
+    /// Three-letter ISO 639-2 codes, like `fra` or `fre`, are expected with `alpha3`. Codes
+    /// reserved for local use (`qaa` to `qtz`) are rejected unless `user_assigned` is set.
+    LanguageCode {
+        alpha3: bool,
+        user_assigned: bool,
+    },
+    /// Multi-line text, any string is accepted
+    ///
+    /// Use `as_multiline_text` to get it with normalized line endings.
+    MultilineText,
+    /// Service unit name, like `nginx`, `nginx.service` or `getty@tty1.service`
+    ///
+    /// Allows the characters of systemd unit names, an optional `@` instance part
+    /// and an optional `.service` or `.socket` suffix, up to 255 chars.
+    ServiceName,
+    /// Valid service unit name known by systemd
+    ///
+    /// Only checks the name validity when `systemctl` is not usable.
+    ExistingServiceName,
+    /// Environment variable name following POSIX rules: letters, digits and `_`,
+    /// not starting with a digit
+    EnvVarName,
+    /// Shell command with balanced quotes and escapes, and at least one word
+    ///
+    /// Only the quoting is checked, not the shell grammar.
+    ShellCommand,
+    /// Duration, as a non-negative number of seconds or a string of numbers with units,
+    /// like `1h30m` or `500ms`
+    ///
+    /// Units are `ms`, `s`, `m`, `h` and `d`.
+    Duration,
+    /// `Duration` not exceeding `max`
+    DurationRange {
+        max: Duration,
+    },
+    /// Comma-separated `key=value` pairs, like `env=prod,team=web`
+    ///
+    /// Spaces around keys and values are trimmed. Values can be empty and contain `=`,
+    /// keys cannot be empty nor repeated. An empty string is an empty list.
+    KeyValueList,
     // TODO extend with usual types for config management
 }
 
+/// Rust enum usable as an attribute type
+///
+/// ```
+/// use rudder_resource::AttributeEnum;
+///
+/// enum State {
+///     Present,
+///     Absent,
+/// }
+///
+/// impl AttributeEnum for State {
+///     fn variants() -> &'static [&'static str] {
+///         &["present", "absent"]
+///     }
+///
+///     fn parse(s: &str) -> Option<Self> {
+///         match s {
+///             "present" => Some(State::Present),
+///             "absent" => Some(State::Absent),
+///             _ => None,
+///         }
+///     }
+/// }
+/// ```
+pub trait AttributeEnum: Sized {
+    /// Names of the variants in policy
+    fn variants() -> &'static [&'static str];
+
+    /// Parse a variant from its name in policy
+    fn parse(s: &str) -> Option<Self>;
+}
+
+/// Type information of an `AttributeType::Enum`
+#[derive(Clone)]
+pub struct EnumType {
+    variants: Vec<String>,
+    parse: fn(&str) -> bool,
+}
+
+impl EnumType {
+    pub fn variants(&self) -> &[String] {
+        &self.variants
+    }
+}
+
+impl PartialEq for EnumType {
+    fn eq(&self, other: &Self) -> bool {
+        self.variants == other.variants
+    }
+}
+
+impl fmt::Debug for EnumType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.variants.fmt(f)
+    }
+}
+
 impl AttributeType {
     pub(crate) fn has_type(&self, value: &Value) -> bool {
         match self {
             AttributeType::Bool => value.as_bool().is_some(),
             AttributeType::String => value.as_str().is_some(),
+            AttributeType::MultilineText => value.as_str().is_some(),
             AttributeType::Integer => value.as_i64().is_some(),
             AttributeType::Float => value.as_f64().is_some(),
             AttributeType::List => value.as_array().is_some(),
@@ -35,10 +308,1578 @@ impl AttributeType {
                 .as_str()
                 .map(|p| Path::new(p).is_absolute())
                 .unwrap_or(false),
+            AttributeType::ExistingFile => Self::as_absolute_path(value)
+                .map(|p| p.is_file())
+                .unwrap_or(false),
+            AttributeType::ExistingDir => Self::as_absolute_path(value)
+                .map(|p| p.is_dir())
+                .unwrap_or(false),
+            AttributeType::ExistingPath => Self::as_absolute_path(value)
+                .map(|p| p.exists())
+                .unwrap_or(false),
+            AttributeType::RelativePath { reject_parent } => value
+                .as_str()
+                .map(Path::new)
+                .map(|p| {
+                    !p.as_os_str().is_empty()
+                        && p.is_relative()
+                        && !(*reject_parent && p.components().any(|c| c == Component::ParentDir))
+                })
+                .unwrap_or(false),
             AttributeType::StringEnum(e) => value
                 .as_str()
                 .map(|s| e.contains(&s.to_owned()))
                 .unwrap_or(false),
+            AttributeType::FileMode => Self::as_file_mode(value).is_some(),
+            AttributeType::Username { leading_digit } => value
+                .as_str()
+                .map(|u| is_username(u, *leading_digit))
+                .unwrap_or(false),
+            AttributeType::ExistingUsername => value
+                .as_str()
+                .map(|u| is_username(u, true) && user_exists(u))
+                .unwrap_or(false),
+            AttributeType::KeyValueList => Self::as_key_value_list(value).is_some(),
+            AttributeType::Duration => Self::as_duration(value).is_some(),
+            AttributeType::DurationRange { max } => {
+                Self::as_duration(value).map(|d| d <= *max).unwrap_or(false)
+            }
+            AttributeType::ShellCommand => Self::as_shell_words(value)
+                .map(|w| !w.is_empty())
+                .unwrap_or(false),
+            AttributeType::EnvVarName => value.as_str().map(is_env_var_name).unwrap_or(false),
+            AttributeType::ServiceName => value.as_str().map(is_service_name).unwrap_or(false),
+            AttributeType::ExistingServiceName => value
+                .as_str()
+                .map(|u| is_service_name(u) && unit_exists(u))
+                .unwrap_or(false),
+            AttributeType::Groupname { leading_digit } => value
+                .as_str()
+                .map(|g| is_username(g, *leading_digit))
+                .unwrap_or(false),
+            AttributeType::ExistingGroupname => value
+                .as_str()
+                .map(|g| is_username(g, true) && group_exists(g))
+                .unwrap_or(false),
+            AttributeType::Hostname => value.as_str().map(is_hostname).unwrap_or(false),
+            AttributeType::Fqdn => value.as_str().map(is_fqdn).unwrap_or(false),
+            AttributeType::Bytes => Self::as_bytes(value).is_some(),
+            AttributeType::Uuid { alternate_forms } => value
+                .as_str()
+                .and_then(|u| parse_uuid(u, *alternate_forms))
+                .is_some(),
+            AttributeType::DateTime => Self::as_date_time(value).is_some(),
+            AttributeType::Nullable(t) => value.is_null() || t.has_type(value),
+            AttributeType::OneOf(types) => types.iter().any(|t| t.has_type(value)),
+            AttributeType::RadixInteger => Self::as_integer(value).is_some(),
+            AttributeType::ListOf(t) => value
+                .as_array()
+                .map(|a| a.iter().all(|v| t.has_type(v)))
+                .unwrap_or(false),
+            AttributeType::Email => value.as_str().map(is_email).unwrap_or(false),
+            AttributeType::Semver => Self::as_semver(value).is_some(),
+            AttributeType::SemverReq => Self::as_semver_req(value).is_some(),
+            AttributeType::NonEmptyString { reject_whitespace } => value
+                .as_str()
+                .map(|s| !(s.is_empty() || *reject_whitespace && s.trim().is_empty()))
+                .unwrap_or(false),
+            AttributeType::MacAddress { unicast_only } => Self::as_mac_address(value)
+                .map(|m| !(*unicast_only && m[0] & 1 == 1))
+                .unwrap_or(false),
+            AttributeType::Cron { seconds } => value
+                .as_str()
+                .map(|c| CronSchedule::parse(c, *seconds).is_ok())
+                .unwrap_or(false),
+            AttributeType::Timezone { reject_aliases } => value
+                .as_str()
+                .map(|z| timezone::zone_exists(z) && !(*reject_aliases && timezone::is_alias(z)))
+                .unwrap_or(false),
+            AttributeType::Base64 {
+                url_safe,
+                lenient,
+                min_bytes,
+                max_bytes,
+            } => value
+                .as_str()
+                .and_then(|b| decode_base64(b, *url_safe, *lenient))
+                .map(|d| d.len() >= *min_bytes && max_bytes.map(|m| d.len() <= m).unwrap_or(true))
+                .unwrap_or(false),
+            AttributeType::Hex { bytes } => Self::as_hex(value)
+                .map(|d| bytes.map(|b| d.len() == b).unwrap_or(true))
+                .unwrap_or(false),
+            AttributeType::Glob => Self::as_glob(value).is_some(),
+            AttributeType::MatchingGlobs(patterns) => value
+                .as_str()
+                .map(|s| {
+                    patterns
+                        .iter()
+                        .filter_map(|p| Glob::new(p).ok())
+                        .any(|g| g.is_match(s))
+                })
+                .unwrap_or(false),
+            AttributeType::JsonString(inner) => Self::as_json_string(value)
+                .map(|v| inner.as_ref().map(|t| t.has_type(&v)).unwrap_or(true))
+                .unwrap_or(false),
+            AttributeType::Enum(e) => value.as_str().map(e.parse).unwrap_or(false),
+            AttributeType::AbsolutePathUnder(base) => Self::as_normalized_path(value)
+                .map(|p| {
+                    normalize_path(base)
+                        .map(|b| p.starts_with(b))
+                        .unwrap_or(false)
+                })
+                .unwrap_or(false),
+            AttributeType::Boolish => Self::as_boolish(value).is_some(),
+            AttributeType::CountryCode {
+                alpha3,
+                user_assigned,
+            } => Self::as_country_code(value)
+                .map(|c| iso::is_country(&c, *alpha3, *user_assigned))
+                .unwrap_or(false),
+            AttributeType::LanguageCode {
+                alpha3,
+                user_assigned,
+            } => Self::as_language_code(value)
+                .map(|c| iso::is_language(&c, *alpha3, *user_assigned))
+                .unwrap_or(false),
+            AttributeType::JsonSchema(schema) => schema::validate(schema, value).is_ok(),
+            AttributeType::StringLength { min, max } => value
+                .as_str()
+                .map(|s| (*min..=*max).contains(&s.chars().count()))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Attribute type accepting the variants of a Rust enum
+    pub fn enumeration<E: AttributeEnum>() -> Self {
+        fn parses<E: AttributeEnum>(s: &str) -> bool {
+            E::parse(s).is_some()
+        }
+
+        AttributeType::Enum(EnumType {
+            variants: E::variants().iter().map(|v| v.to_string()).collect(),
+            parse: parses::<E>,
+        })
+    }
+
+    /// Parse an `Enum` value into its variant
+    pub fn as_enum<E: AttributeEnum>(value: &Value) -> Option<E> {
+        value.as_str().and_then(E::parse)
+    }
+
+    /// Index of the matched variant of a `StringEnum`
+    ///
+    /// Returns `None` for other types, or when the value matches no variant.
+    pub fn enum_index(&self, value: &Value) -> Option<usize> {
+        match self {
+            AttributeType::StringEnum(e) => {
+                value.as_str().and_then(|s| e.iter().position(|v| v == s))
+            }
+            _ => None,
+        }
+    }
+
+    fn as_absolute_path(value: &Value) -> Option<&Path> {
+        value.as_str().map(Path::new).filter(|p| p.is_absolute())
+    }
+
+    /// Parse a `MacAddress` value into its octets
+    pub fn as_mac_address(value: &Value) -> Option<[u8; 6]> {
+        value.as_str().and_then(parse_mac_address)
+    }
+
+    /// Parse a `Cron` value, with or without a seconds field
+    pub fn as_cron(value: &Value) -> Option<CronSchedule> {
+        value
+            .as_str()
+            .and_then(|c| CronSchedule::parse(c, true).ok())
+    }
+
+    /// Load the zone of a `Timezone` value
+    pub fn as_timezone(value: &Value) -> Option<TimeZone> {
+        value.as_str().and_then(|z| TimeZone::load(z).ok())
+    }
+
+    /// Decode a `Base64` value, in any accepted form
+    pub fn as_base64(value: &Value) -> Option<Vec<u8>> {
+        value
+            .as_str()
+            .and_then(|b| decode_base64(b, false, true).or_else(|| decode_base64(b, true, true)))
+    }
+
+    /// Decode a `Hex` value into its bytes
+    pub fn as_hex(value: &Value) -> Option<Vec<u8>> {
+        let s = value.as_str()?;
+        let digits = s.strip_prefix("0x").unwrap_or(s);
+        if digits.is_empty()
+            || digits.len() % 2 != 0
+            || !digits.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return None;
+        }
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+            .collect()
+    }
+
+    /// Compile a `Glob` value
+    pub fn as_glob(value: &Value) -> Option<Glob> {
+        value.as_str().and_then(|g| Glob::new(g).ok())
+    }
+
+    /// Decode a `JsonString` value
+    pub fn as_json_string(value: &Value) -> Option<Value> {
+        value.as_str().and_then(|j| serde_json::from_str(j).ok())
+    }
+
+    /// Parse a `Boolish` value
+    pub fn as_boolish(value: &Value) -> Option<bool> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            Value::Number(n) => match n.as_u64() {
+                Some(1) => Some(true),
+                Some(0) => Some(false),
+                _ => None,
+            },
+            Value::String(s) => match s.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Some(true),
+                "false" | "no" | "off" | "0" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Get a `MultilineText` value, with CRLF and CR line endings replaced by LF if `normalize`
+    pub fn as_multiline_text(value: &Value, normalize: bool) -> Option<String> {
+        let text = value.as_str()?;
+        Some(if normalize {
+            text.replace("\r\n", "\n").replace('\r', "\n")
+        } else {
+            text.to_string()
+        })
+    }
+
+    /// Split a `ShellCommand` value into words, like a POSIX shell
+    ///
+    /// Handles single and double quotes and backslash escapes, without any expansion.
+    pub fn as_shell_words(value: &Value) -> Option<Vec<String>> {
+        value.as_str().and_then(split_shell_words)
+    }
+
+    /// Parse a `KeyValueList` value into a map
+    pub fn as_key_value_list(value: &Value) -> Option<BTreeMap<String, String>> {
+        value.as_str().and_then(parse_key_value_list)
+    }
+
+    /// Parse a `Duration` or `DurationRange` value
+    pub fn as_duration(value: &Value) -> Option<Duration> {
+        match value {
+            Value::String(s) => parse_duration(s.trim()),
+            Value::Number(n) => n.as_u64().map(Duration::from_secs),
+            _ => None,
+        }
+    }
+
+    /// Canonical uppercase form of a `CountryCode` value
+    pub fn as_country_code(value: &Value) -> Option<String> {
+        value.as_str().map(|c| c.to_ascii_uppercase())
+    }
+
+    /// Canonical lowercase form of a `LanguageCode` value
+    pub fn as_language_code(value: &Value) -> Option<String> {
+        value.as_str().map(|c| c.to_ascii_lowercase())
+    }
+
+    /// Normalize an absolute path value, like for `AbsolutePathUnder`
+    ///
+    /// Returns `None` for relative paths and paths going above the root.
+    pub fn as_normalized_path(value: &Value) -> Option<PathBuf> {
+        value.as_str().and_then(|p| normalize_path(Path::new(p)))
+    }
+
+    /// Parse a `FileMode` value into a mode
+    pub fn as_file_mode(value: &Value) -> Option<u32> {
+        let digits = match value {
+            Value::String(s) if (3..=4).contains(&s.len()) => s.clone(),
+            // Integers lose their leading zeroes
+            Value::Number(n) => n.as_u64()?.to_string(),
+            _ => return None,
+        };
+        if digits.len() > 4 || !digits.chars().all(|c| c.is_digit(8)) {
+            return None;
+        }
+        // Cannot overflow nor exceed 0o7777 with at most 4 octal digits
+        u32::from_str_radix(&digits, 8).ok()
+    }
+
+    /// Parse a `Bytes` value into a number of bytes
+    pub fn as_bytes(value: &Value) -> Option<u64> {
+        let s = match value {
+            Value::String(s) => s.trim(),
+            Value::Number(n) => return n.as_u64(),
+            _ => return None,
+        };
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        if number.is_empty() {
+            return None;
+        }
+        let multiplier: u64 = match unit.trim_start() {
+            "" | "B" => 1,
+            "kB" => 1000,
+            "MB" => 1000u64.pow(2),
+            "GB" => 1000u64.pow(3),
+            "TB" => 1000u64.pow(4),
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            _ => return None,
+        };
+        number.parse::<u64>().ok()?.checked_mul(multiplier)
+    }
+
+    /// Parse a `Uuid` value, in any accepted form, into its bytes
+    pub fn as_uuid(value: &Value) -> Option<[u8; 16]> {
+        value.as_str().and_then(|u| parse_uuid(u, true))
+    }
+
+    /// Parse a `RadixInteger` value into an integer
+    pub fn as_integer(value: &Value) -> Option<i64> {
+        let s = match value {
+            Value::String(s) => s.as_str(),
+            Value::Number(n) => return n.as_i64(),
+            _ => return None,
+        };
+        let (sign, unsigned) = match s.strip_prefix('-') {
+            Some(u) => ("-", u),
+            None => ("", s),
+        };
+        let (radix, digits) = if let Some(d) = unsigned.strip_prefix("0x") {
+            (16, d)
+        } else if let Some(d) = unsigned.strip_prefix("0o") {
+            (8, d)
+        } else {
+            (10, unsigned)
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+        // Keep the sign to allow parsing `i64::MIN`
+        i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok()
+    }
+
+    /// Parse a `Semver` value
+    pub fn as_semver(value: &Value) -> Option<Version> {
+        value.as_str().and_then(|v| v.parse().ok())
+    }
+
+    /// Parse a `SemverReq` value
+    pub fn as_semver_req(value: &Value) -> Option<VersionReq> {
+        value.as_str().and_then(|v| v.parse().ok())
+    }
+
+    /// Get a `Nullable` value, `None` if it is explicitly `null`
+    pub fn as_nullable(value: &Value) -> Option<&Value> {
+        if value.is_null() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Parse a `DateTime` value into a UTC date and time
+    ///
+    /// Leap seconds are represented by chrono as an extra second of nanoseconds.
+    pub fn as_date_time(value: &Value) -> Option<DateTime<Utc>> {
+        value
+            .as_str()
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map(|d| d.with_timezone(&Utc))
+    }
+}
+
+/// Typed attributes of a promise
+///
+/// Can be derived with the `derive` feature, and used to declare the attributes
+/// of a promise type with the `attributes!` macro.
+pub trait PromiseAttributes: Sized {
+    fn required_attributes() -> Vec<(String, AttributeType)>;
+    fn optional_attributes() -> Vec<(String, AttributeType)>;
+
+    /// Build from the promise attributes
+    ///
+    /// Missing attributes are deserialized from `null`.
+    fn from_attributes(attributes: &Attributes) -> Result<Self, String>;
+}
+
+#[doc(hidden)]
+pub fn __attribute_value<T: DeserializeOwned>(
+    attributes: &Attributes,
+    name: &str,
+) -> Result<T, String> {
+    let value = attributes.get(name).cloned().unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|e| format!("Attribute {}: {}", name, e))
+}
+
+/// Attributes of a promise
+///
+/// Dereferences to the underlying JSON map, and provides typed accessors
+/// returning `None` when the attribute is missing or has another type.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Attributes(Map<String, Value>);
+
+impl Attributes {
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.0.get(key).and_then(|v| v.as_bool())
+    }
+
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.0.get(key).and_then(|v| v.as_i64())
+    }
+
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.0.get(key).and_then(|v| v.as_f64())
+    }
+
+    pub fn get_list(&self, key: &str) -> Option<&Vec<Value>> {
+        self.0.get(key).and_then(|v| v.as_array())
+    }
+
+    pub fn get_data(&self, key: &str) -> Option<&Map<String, Value>> {
+        self.0.get(key).and_then(|v| v.as_object())
+    }
+
+    pub fn get_path(&self, key: &str) -> Option<&Path> {
+        self.get_str(key).map(Path::new)
+    }
+
+    /// Deserialize the attributes into a typed structure
+    ///
+    /// The error can be used as an `Invalid` validation result.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_value(Value::Object(self.0.clone()))
+            .map_err(|e| format!("Invalid attributes: {}", e))
+    }
+
+    /// Copy of the attributes with the values of the given ones replaced by `***`
+    pub(crate) fn redacted(&self, secrets: &[String]) -> Self {
+        let mut redacted = self.clone();
+        for secret in secrets {
+            if let Some(value) = redacted.0.get_mut(secret) {
+                *value = Value::String("***".to_string());
+            }
+        }
+        redacted
+    }
+
+    /// Copy of the attributes with values larger than `max_bytes` once serialized
+    /// replaced by a placeholder string
+    pub(crate) fn truncated(&self, max_bytes: usize) -> Self {
+        let mut truncated = self.clone();
+        for value in truncated.0.values_mut() {
+            let size = value.to_string().len();
+            if size > max_bytes {
+                *value = Value::String(format!("<truncated {} bytes>", size));
+            }
+        }
+        truncated
+    }
+
+    /// Get the underlying JSON map
+    pub fn into_inner(self) -> Map<String, Value> {
+        self.0
+    }
+}
+
+impl From<Map<String, Value>> for Attributes {
+    fn from(map: Map<String, Value>) -> Self {
+        Self(map)
+    }
+}
+
+impl Deref for Attributes {
+    type Target = Map<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Attributes {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Parse `k=v` pairs, `None` for empty or repeated keys
+fn parse_key_value_list(list: &str) -> Option<BTreeMap<String, String>> {
+    let mut pairs = BTreeMap::new();
+    if list.trim().is_empty() {
+        return Some(pairs);
+    }
+    for pair in list.split(',') {
+        let (key, value) = pair.split_once('=')?;
+        let key = key.trim();
+        if key.is_empty() || pairs.contains_key(key) {
+            return None;
         }
+        pairs.insert(key.to_string(), value.trim().to_string());
+    }
+    Some(pairs)
+}
+
+/// Parse a sequence of numbers with units, like `1h30m`, a lone number being seconds
+fn parse_duration(s: &str) -> Option<Duration> {
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    if s.is_empty() {
+        return None;
+    }
+    let mut rest = s;
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: u64 = number.parse().ok()?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let part = match unit {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number.checked_mul(60)?),
+            "h" => Duration::from_secs(number.checked_mul(3600)?),
+            "d" => Duration::from_secs(number.checked_mul(86400)?),
+            _ => return None,
+        };
+        total = total.checked_add(part)?;
+        rest = tail;
+    }
+    Some(total)
+}
+
+/// Split a command into words, `None` for unterminated quotes or escapes
+fn split_shell_words(command: &str) -> Option<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(w) = word.take() {
+                    words.push(w);
+                }
+            }
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => w.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            // Line continuation
+                            '\n' => (),
+                            c @ ('$' | '`' | '"' | '\\') => w.push(c),
+                            c => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                        },
+                        c => w.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next()? {
+                '\n' => (),
+                c => word.get_or_insert_with(String::new).push(c),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+fn is_env_var_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(first) => {
+            !first.is_ascii_digit() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+fn is_service_name(name: &str) -> bool {
+    let unit = name
+        .strip_suffix(".service")
+        .or_else(|| name.strip_suffix(".socket"))
+        .unwrap_or(name);
+    let (prefix, instance) = match unit.split_once('@') {
+        Some((p, i)) => (p, Some(i)),
+        None => (unit, None),
+    };
+    let valid = |s: &str| {
+        s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":-_.\\".contains(c))
+    };
+    // Would be read as an option by systemctl
+    name.len() <= 255
+        && !prefix.is_empty()
+        && !prefix.starts_with('-')
+        && valid(prefix)
+        && instance.map(valid).unwrap_or(true)
+}
+
+/// Command getting the load state of the unit
+fn unit_state_command(name: &str) -> std::process::Command {
+    let mut command = std::process::Command::new("systemctl");
+    command.args(["show", "--property=LoadState", "--value", "--", name]);
+    command
+}
+
+/// Whether systemd knows the unit, `true` when it can't be asked
+fn unit_exists(name: &str) -> bool {
+    match unit_state_command(name).output() {
+        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim() != "not-found",
+        _ => true,
+    }
+}
+
+fn is_username(name: &str, leading_digit: bool) -> bool {
+    let first = match name.chars().next() {
+        Some(c) => c,
+        None => return false,
+    };
+    name.len() <= 32
+        && first != '-'
+        && (leading_digit || !first.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+fn is_hostname(name: &str) -> bool {
+    // The trailing dot represents the root zone
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn is_fqdn(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    match name.rsplit_once('.') {
+        Some((_, tld)) => is_hostname(name) && !tld.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+fn is_email(address: &str) -> bool {
+    match address.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !address.chars().any(char::is_whitespace)
+                && domain.contains('.')
+                && !domain.ends_with('.')
+                && is_hostname(domain)
+        }
+        None => false,
+    }
+}
+
+fn normalize_path(path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() {
+        return None;
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            // The root cannot be popped, it would be a traversal above it
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            c => normalized.push(c),
+        }
+    }
+    Some(normalized)
+}
+
+fn parse_mac_address(mac: &str) -> Option<[u8; 6]> {
+    let groups: Vec<&str> = if mac.contains('.') {
+        mac.split('.').collect()
+    } else if mac.contains(':') {
+        mac.split(':').collect()
+    } else {
+        mac.split('-').collect()
+    };
+    let group_len = match groups.len() {
+        6 => 2,
+        3 if mac.contains('.') => 4,
+        _ => return None,
+    };
+    if groups
+        .iter()
+        .any(|g| g.len() != group_len || !g.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+    let digits = groups.concat();
+    let mut mac = [0; 6];
+    for (i, octet) in mac.iter_mut().enumerate() {
+        *octet = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(mac)
+}
+
+fn decode_base64(encoded: &str, url_safe: bool, lenient: bool) -> Option<Vec<u8>> {
+    let encoded: String = if lenient {
+        encoded
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect()
+    } else {
+        encoded.to_string()
+    };
+    let data = encoded.trim_end_matches('=');
+    let padding = encoded.len() - data.len();
+    let padded = encoded.len().is_multiple_of(4);
+    if padding > 2 || (padding > 0 || !url_safe) && !padded || data.len() % 4 == 1 {
+        return None;
+    }
+    let (c62, c63) = if url_safe { (b'-', b'_') } else { (b'+', b'/') };
+
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in data.bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            c if c == c62 => 62,
+            c if c == c63 => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(sextet);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // Reject non-canonical encodings with unused bits set
+    if bits != 0 {
+        return None;
+    }
+    Some(decoded)
+}
+
+fn parse_uuid(uuid: &str, alternate_forms: bool) -> Option<[u8; 16]> {
+    let uuid = if alternate_forms {
+        uuid.strip_prefix("urn:uuid:")
+            .or_else(|| uuid.strip_prefix('{').and_then(|u| u.strip_suffix('}')))
+            .unwrap_or(uuid)
+    } else {
+        uuid
+    };
+    let groups: Vec<&str> = uuid.split('-').collect();
+    if groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12]) {
+        return None;
+    }
+    let hex = groups.concat();
+    let mut bytes = [0; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let pair = hex.get(i * 2..i * 2 + 2)?;
+        if !pair.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(unix)]
+fn user_exists(name: &str) -> bool {
+    use std::ffi::{c_char, c_void, CString};
+
+    extern "C" {
+        fn getpwnam(name: *const c_char) -> *mut c_void;
+    }
+
+    let name = match CString::new(name) {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+    // SAFETY: name is a valid nul-terminated string, and we only check the returned pointer
+    unsafe { !getpwnam(name.as_ptr()).is_null() }
+}
+
+#[cfg(not(unix))]
+fn user_exists(_name: &str) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn group_exists(name: &str) -> bool {
+    use std::ffi::{c_char, c_void, CString};
+
+    extern "C" {
+        fn getgrnam(name: *const c_char) -> *mut c_void;
+    }
+
+    let name = match CString::new(name) {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+    // SAFETY: name is a valid nul-terminated string, and we only check the returned pointer
+    unsafe { !getgrnam(name.as_ptr()).is_null() }
+}
+
+#[cfg(not(unix))]
+fn group_exists(_name: &str) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_gets_typed_attributes() {
+        let attributes = Attributes::from(
+            json!({"path": "/tmp", "force": true, "count": 3, "lines": ["a"]})
+                .as_object()
+                .unwrap()
+                .clone(),
+        );
+        assert_eq!(attributes.get_str("path"), Some("/tmp"));
+        assert_eq!(attributes.get_path("path"), Some(Path::new("/tmp")));
+        assert_eq!(attributes.get_bool("force"), Some(true));
+        assert_eq!(attributes.get_i64("count"), Some(3));
+        assert_eq!(attributes.get_list("lines"), Some(&vec![json!("a")]));
+        assert_eq!(attributes.get_str("count"), None);
+        assert_eq!(attributes.get_str("missing"), None);
+        assert_eq!(attributes.len(), 4);
+    }
+
+    #[test]
+    fn it_deserializes_attributes() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Args {
+            path: String,
+            force: Option<bool>,
+        }
+
+        let attributes = Attributes::from(json!({"path": "/tmp"}).as_object().unwrap().clone());
+        assert_eq!(
+            attributes.deserialize::<Args>(),
+            Ok(Args {
+                path: "/tmp".to_string(),
+                force: None
+            })
+        );
+        let attributes = Attributes::from(json!({"path": 3}).as_object().unwrap().clone());
+        assert!(attributes
+            .deserialize::<Args>()
+            .unwrap_err()
+            .starts_with("Invalid attributes: invalid type: integer `3`"));
+    }
+
+    #[test]
+    fn it_parses_file_modes() {
+        assert_eq!(AttributeType::as_file_mode(&json!("0644")), Some(0o644));
+        assert_eq!(AttributeType::as_file_mode(&json!("755")), Some(0o755));
+        assert_eq!(AttributeType::as_file_mode(&json!("4755")), Some(0o4755));
+        assert_eq!(AttributeType::as_file_mode(&json!(644)), Some(0o644));
+        assert_eq!(AttributeType::as_file_mode(&json!("0648")), None);
+        assert_eq!(AttributeType::as_file_mode(&json!("07777")), None);
+        assert_eq!(AttributeType::as_file_mode(&json!("77")), None);
+        assert_eq!(AttributeType::as_file_mode(&json!("u+x")), None);
+        assert_eq!(AttributeType::as_file_mode(&json!(-644)), None);
+        assert_eq!(AttributeType::as_file_mode(&json!(19)), None);
+    }
+
+    #[test]
+    fn it_parses_bytes() {
+        assert_eq!(AttributeType::as_bytes(&json!(512)), Some(512));
+        assert_eq!(AttributeType::as_bytes(&json!("512")), Some(512));
+        assert_eq!(AttributeType::as_bytes(&json!("512B")), Some(512));
+        assert_eq!(AttributeType::as_bytes(&json!("10kB")), Some(10_000));
+        assert_eq!(AttributeType::as_bytes(&json!("10 KiB")), Some(10_240));
+        assert_eq!(AttributeType::as_bytes(&json!("10MiB")), Some(10_485_760));
+        assert_eq!(AttributeType::as_bytes(&json!("2GB")), Some(2_000_000_000));
+        assert_eq!(AttributeType::as_bytes(&json!("10KB")), None);
+        assert_eq!(AttributeType::as_bytes(&json!("-10MiB")), None);
+        assert_eq!(AttributeType::as_bytes(&json!(-10)), None);
+        assert_eq!(AttributeType::as_bytes(&json!("1.5GiB")), None);
+        assert_eq!(AttributeType::as_bytes(&json!("MiB")), None);
+        assert_eq!(AttributeType::as_bytes(&json!("99999999999TiB")), None);
+    }
+
+    #[test]
+    fn it_parses_uuids() {
+        let canonical = AttributeType::Uuid {
+            alternate_forms: false,
+        };
+        let alternate = AttributeType::Uuid {
+            alternate_forms: true,
+        };
+        let uuid = "0f8fad5b-d9cb-469f-a165-70867728950e";
+        assert!(canonical.has_type(&json!(uuid)));
+        assert!(canonical.has_type(&json!(uuid.to_uppercase())));
+        assert!(!canonical.has_type(&json!(format!("{{{}}}", uuid))));
+        assert!(alternate.has_type(&json!(format!("{{{}}}", uuid))));
+        assert!(alternate.has_type(&json!(format!("urn:uuid:{}", uuid))));
+        assert!(!alternate.has_type(&json!("0f8fad5bd9cb469fa16570867728950e")));
+        assert!(!alternate.has_type(&json!("0f8fad5b-d9cb-469f-a165-70867728950g")));
+        assert!(!alternate.has_type(&json!("0f8fad5b-d9cb-469f-a165+70867728950e")));
+        assert_eq!(
+            AttributeType::as_uuid(&json!(uuid)).unwrap()[..4],
+            [0x0f, 0x8f, 0xad, 0x5b]
+        );
+    }
+
+    #[test]
+    fn it_parses_mac_addresses() {
+        let mac = Some([0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e]);
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("00:1a:2b:3c:4d:5e")),
+            mac
+        );
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("00-1A-2B-3C-4D-5E")),
+            mac
+        );
+        assert_eq!(AttributeType::as_mac_address(&json!("001a.2b3c.4d5e")), mac);
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("00:1a:2b:3c:4d")),
+            None
+        );
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("00:1a-2b:3c:4d:5e")),
+            None
+        );
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("0:1a:2b:3c:4d:5e")),
+            None
+        );
+        assert_eq!(
+            AttributeType::as_mac_address(&json!("00:1a:2b:3c:4d:5g")),
+            None
+        );
+        assert_eq!(AttributeType::as_mac_address(&json!("001a2b3c4d5e")), None);
+
+        let any = AttributeType::MacAddress {
+            unicast_only: false,
+        };
+        let unicast = AttributeType::MacAddress { unicast_only: true };
+        assert!(any.has_type(&json!("ff:ff:ff:ff:ff:ff")));
+        assert!(!unicast.has_type(&json!("ff:ff:ff:ff:ff:ff")));
+        assert!(!unicast.has_type(&json!("01:00:5e:00:00:01")));
+        assert!(unicast.has_type(&json!("00:1a:2b:3c:4d:5e")));
+    }
+
+    #[test]
+    fn it_checks_cron_schedules() {
+        let cron = AttributeType::Cron { seconds: false };
+        assert!(cron.has_type(&json!("*/5 * * * mon-fri")));
+        assert!(cron.has_type(&json!("@daily")));
+        assert!(!cron.has_type(&json!("0 */5 * * * *")));
+        assert!(!cron.has_type(&json!("60 * * * *")));
+        assert!(AttributeType::Cron { seconds: true }.has_type(&json!("0 */5 * * * *")));
+        assert!(AttributeType::as_cron(&json!("0 0 1 1 *")).is_some());
+    }
+
+    #[test]
+    fn it_checks_base64_data() {
+        let strict = AttributeType::Base64 {
+            url_safe: false,
+            lenient: false,
+            min_bytes: 0,
+            max_bytes: None,
+        };
+        assert!(strict.has_type(&json!("aGVsbG8/Pz4+")));
+        assert!(strict.has_type(&json!("aGVsbG8=")));
+        assert!(strict.has_type(&json!("")));
+        assert!(!strict.has_type(&json!("aGVsbG8")));
+        assert!(!strict.has_type(&json!("aGVs\nbG8=")));
+        assert!(!strict.has_type(&json!("aGVsbG9=")));
+        assert!(!strict.has_type(&json!("aGVsbG8_Pz4-")));
+        assert!(!strict.has_type(&json!("a===")));
+
+        let url_safe = AttributeType::Base64 {
+            url_safe: true,
+            lenient: true,
+            min_bytes: 1,
+            max_bytes: Some(5),
+        };
+        assert!(url_safe.has_type(&json!("aGVsbG8")));
+        assert!(url_safe.has_type(&json!("aGVs\nbG8=")));
+        assert!(!url_safe.has_type(&json!("aGVsbG8/")));
+        assert!(!url_safe.has_type(&json!("")));
+        assert!(!url_safe.has_type(&json!("aGVsbG8hIQ")));
+
+        assert_eq!(
+            AttributeType::as_base64(&json!("aGVsbG8/Pz4+")),
+            Some(b"hello??>>".to_vec())
+        );
+        assert_eq!(
+            AttributeType::as_base64(&json!("aGVsbG8_Pz4-")),
+            Some(b"hello??>>".to_vec())
+        );
+    }
+
+    #[test]
+    fn it_parses_hex_bytes() {
+        let any = AttributeType::Hex { bytes: None };
+        assert!(any.has_type(&json!("00ffAB")));
+        assert!(any.has_type(&json!("0x00ff")));
+        assert!(!any.has_type(&json!("0x0ff")));
+        assert!(!any.has_type(&json!("0g")));
+        assert!(!any.has_type(&json!("+0ff")));
+        assert!(!any.has_type(&json!("")));
+        assert!(!any.has_type(&json!("0x")));
+        assert!(AttributeType::Hex { bytes: Some(2) }.has_type(&json!("beef")));
+        assert!(!AttributeType::Hex { bytes: Some(2) }.has_type(&json!("beefed")));
+        assert_eq!(
+            AttributeType::as_hex(&json!("0xBEEF")),
+            Some(vec![0xbe, 0xef])
+        );
+    }
+
+    #[test]
+    fn it_checks_globs() {
+        assert!(AttributeType::Glob.has_type(&json!("/etc/*.conf")));
+        assert!(!AttributeType::Glob.has_type(&json!("/etc/[a-")));
+        assert!(AttributeType::as_glob(&json!("*.conf"))
+            .unwrap()
+            .is_match("ssl.conf"));
+
+        let config = AttributeType::MatchingGlobs(vec!["*.conf".to_string(), "*.ini".to_string()]);
+        assert!(config.has_type(&json!("php.ini")));
+        assert!(!config.has_type(&json!("php.json")));
+    }
+
+    #[test]
+    fn it_parses_date_times() {
+        let parse = |s: &str| AttributeType::as_date_time(&json!(s));
+        let epoch = |secs: i64| DateTime::from_timestamp(secs, 0);
+
+        assert_eq!(parse("1970-01-01T00:00:00Z"), epoch(0));
+        assert_eq!(parse("2021-06-01T12:30:00Z"), epoch(1_622_550_600));
+        assert_eq!(parse("2021-06-01T14:30:00+02:00"), epoch(1_622_550_600));
+        assert_eq!(
+            parse("2021-06-01 12:30:00.25Z"),
+            DateTime::from_timestamp(1_622_550_600, 250_000_000)
+        );
+        assert_eq!(
+            parse("2016-12-31T23:59:60Z").map(|d| d.timestamp()),
+            Some(1_483_228_799)
+        );
+        assert_eq!(parse("2020-02-29T00:00:00Z"), epoch(1_582_934_400));
+        assert_eq!(parse("1969-12-31T23:00:00-01:00"), epoch(0));
+        assert!(parse("1960-01-01T00:00:00Z").is_some());
+        assert_eq!(parse("2023-02-30T00:00:00Z"), None);
+        assert_eq!(parse("2021-02-29T00:00:00Z"), None);
+        assert_eq!(parse("2021-06-01T12:30:00"), None);
+        assert_eq!(parse("2021-06-01T24:00:00Z"), None);
+        assert_eq!(parse("2021-06-01T12:30:00.Z"), None);
+        assert_eq!(parse("2021-06-01T12:30:00+2:00"), None);
+        assert_eq!(parse("2021-06-01"), None);
+    }
+
+    #[test]
+    fn it_checks_nullable_values() {
+        let nullable = AttributeType::Nullable(Box::new(AttributeType::Integer));
+        assert!(nullable.has_type(&Value::Null));
+        assert!(nullable.has_type(&json!(42)));
+        assert!(!nullable.has_type(&json!("42")));
+        assert_eq!(AttributeType::as_nullable(&Value::Null), None);
+        assert_eq!(AttributeType::as_nullable(&json!(42)), Some(&json!(42)));
+    }
+
+    #[test]
+    fn it_gets_enum_indexes() {
+        let state = AttributeType::StringEnum(vec!["present".to_string(), "absent".to_string()]);
+        assert_eq!(state.enum_index(&json!("present")), Some(0));
+        assert_eq!(state.enum_index(&json!("absent")), Some(1));
+        assert_eq!(state.enum_index(&json!("unknown")), None);
+        assert_eq!(state.enum_index(&json!(0)), None);
+        assert_eq!(AttributeType::String.enum_index(&json!("present")), None);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Service {
+        Running,
+        Stopped,
+    }
+
+    impl AttributeEnum for Service {
+        fn variants() -> &'static [&'static str] {
+            &["running", "stopped"]
+        }
+
+        fn parse(s: &str) -> Option<Self> {
+            match s {
+                "running" => Some(Service::Running),
+                "stopped" => Some(Service::Stopped),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn it_checks_rust_enums() {
+        let service = AttributeType::enumeration::<Service>();
+        assert!(service.has_type(&json!("running")));
+        assert!(!service.has_type(&json!("restarted")));
+        assert!(!service.has_type(&json!(1)));
+        assert_eq!(format!("{:?}", service), r#"Enum(["running", "stopped"])"#);
+        assert_eq!(
+            AttributeType::as_enum::<Service>(&json!("stopped")),
+            Some(Service::Stopped)
+        );
+    }
+
+    #[test]
+    fn it_checks_union_types() {
+        let port = AttributeType::OneOf(vec![AttributeType::Integer, AttributeType::String]);
+        assert!(port.has_type(&json!(22)));
+        assert!(port.has_type(&json!("ssh")));
+        assert!(!port.has_type(&json!(true)));
+        assert!(!AttributeType::OneOf(vec![]).has_type(&json!(22)));
+    }
+
+    #[test]
+    fn it_parses_radix_integers() {
+        let parse = |v: Value| AttributeType::as_integer(&v);
+        assert_eq!(parse(json!(42)), Some(42));
+        assert_eq!(parse(json!("42")), Some(42));
+        assert_eq!(parse(json!("-42")), Some(-42));
+        assert_eq!(parse(json!("0x1F")), Some(31));
+        assert_eq!(parse(json!("0o022")), Some(0o22));
+        assert_eq!(parse(json!("-0x8000000000000000")), Some(i64::MIN));
+        assert_eq!(parse(json!("0x8000000000000000")), None);
+        assert_eq!(parse(json!("0o8")), None);
+        assert_eq!(parse(json!("0x")), None);
+        assert_eq!(parse(json!("0x-1")), None);
+        assert_eq!(parse(json!("+1")), None);
+        assert_eq!(parse(json!(1.5)), None);
+    }
+
+    #[test]
+    fn it_checks_emails() {
+        assert!(AttributeType::Email.has_type(&json!("admin@rudder.io")));
+        assert!(AttributeType::Email.has_type(&json!("first.last+tag@mail.rudder.io")));
+        assert!(AttributeType::Email.has_type(&json!("\"odd@local\"@rudder.io")));
+        assert!(!AttributeType::Email.has_type(&json!("admin@localhost")));
+        assert!(!AttributeType::Email.has_type(&json!("@rudder.io")));
+        assert!(!AttributeType::Email.has_type(&json!("admin rudder@rudder.io")));
+        assert!(!AttributeType::Email.has_type(&json!("admin@rudder.io.")));
+        assert!(!AttributeType::Email.has_type(&json!("rudder.io")));
+
+        let recipients = AttributeType::ListOf(Box::new(AttributeType::Email));
+        assert!(recipients.has_type(&json!(["admin@rudder.io", "ops@rudder.io"])));
+        assert!(recipients.has_type(&json!([])));
+        assert!(!recipients.has_type(&json!(["admin@rudder.io", "ops"])));
+        assert!(!recipients.has_type(&json!("admin@rudder.io")));
+    }
+
+    #[test]
+    fn it_checks_semantic_versions() {
+        assert!(AttributeType::Semver.has_type(&json!("1.2.3")));
+        assert!(!AttributeType::Semver.has_type(&json!("1.2.x")));
+        assert!(AttributeType::SemverReq.has_type(&json!(">=1.2, <2")));
+        assert!(AttributeType::SemverReq.has_type(&json!("1.2.x")));
+        assert!(!AttributeType::SemverReq.has_type(&json!("latest")));
+
+        let req = AttributeType::as_semver_req(&json!("^1.2")).unwrap();
+        assert!(req.matches(&AttributeType::as_semver(&json!("1.4.0")).unwrap()));
+    }
+
+    #[test]
+    fn it_checks_string_lengths() {
+        let label = AttributeType::StringLength { min: 1, max: 4 };
+        assert!(label.has_type(&json!("a")));
+        assert!(label.has_type(&json!("été!")));
+        assert!(!label.has_type(&json!("")));
+        assert!(!label.has_type(&json!("abcde")));
+        assert!(!label.has_type(&json!(42)));
+    }
+
+    #[test]
+    fn it_checks_non_empty_strings() {
+        let non_empty = AttributeType::NonEmptyString {
+            reject_whitespace: false,
+        };
+        let non_blank = AttributeType::NonEmptyString {
+            reject_whitespace: true,
+        };
+        assert!(non_empty.has_type(&json!("a")));
+        assert!(non_empty.has_type(&json!(" \t")));
+        assert!(!non_empty.has_type(&json!("")));
+        assert!(non_blank.has_type(&json!(" a ")));
+        assert!(!non_blank.has_type(&json!(" \t")));
+        assert!(!non_blank.has_type(&json!(42)));
+    }
+
+    #[test]
+    fn it_checks_existing_paths() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("rudder_resource_existing_{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let dir = json!(dir.to_str().unwrap());
+        let file = json!(path.to_str().unwrap());
+        let missing = json!("/nonexistent/rudder_resource");
+
+        assert!(AttributeType::ExistingFile.has_type(&file));
+        assert!(!AttributeType::ExistingFile.has_type(&dir));
+        assert!(AttributeType::ExistingDir.has_type(&dir));
+        assert!(!AttributeType::ExistingDir.has_type(&file));
+        assert!(AttributeType::ExistingPath.has_type(&file));
+        assert!(AttributeType::ExistingPath.has_type(&dir));
+        assert!(!AttributeType::ExistingPath.has_type(&missing));
+        assert!(!AttributeType::ExistingPath.has_type(&json!(".")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_checks_country_and_language_codes() {
+        let country = AttributeType::CountryCode {
+            alpha3: false,
+            user_assigned: false,
+        };
+        assert!(country.has_type(&json!("fr")));
+        assert!(!country.has_type(&json!("XX")));
+        assert!(!country.has_type(&json!("FRA")));
+        assert_eq!(
+            AttributeType::as_country_code(&json!("fr")),
+            Some("FR".to_string())
+        );
+
+        let language = AttributeType::LanguageCode {
+            alpha3: true,
+            user_assigned: false,
+        };
+        assert!(language.has_type(&json!("DEU")));
+        assert!(language.has_type(&json!("ger")));
+        assert!(!language.has_type(&json!("de")));
+        assert_eq!(
+            AttributeType::as_language_code(&json!("DEU")),
+            Some("deu".to_string())
+        );
+    }
+
+    #[test]
+    fn it_normalizes_line_endings() {
+        let text = json!("line 1\r\nline 2\rline 3\n");
+        assert!(AttributeType::MultilineText.has_type(&text));
+        assert!(!AttributeType::MultilineText.has_type(&json!(["line 1"])));
+        assert_eq!(
+            AttributeType::as_multiline_text(&text, true),
+            Some("line 1\nline 2\nline 3\n".to_string())
+        );
+        assert_eq!(
+            AttributeType::as_multiline_text(&text, false),
+            Some("line 1\r\nline 2\rline 3\n".to_string())
+        );
+    }
+
+    #[test]
+    fn it_parses_boolish_values() {
+        let parse = |v: Value| AttributeType::as_boolish(&v);
+        assert_eq!(parse(json!(true)), Some(true));
+        assert_eq!(parse(json!("Yes")), Some(true));
+        assert_eq!(parse(json!("on")), Some(true));
+        assert_eq!(parse(json!(1)), Some(true));
+        assert_eq!(parse(json!("OFF")), Some(false));
+        assert_eq!(parse(json!("0")), Some(false));
+        assert_eq!(parse(json!(0)), Some(false));
+        assert_eq!(parse(json!("y")), None);
+        assert_eq!(parse(json!(2)), None);
+        assert_eq!(parse(json!(1.0)), None);
+        assert_eq!(parse(json!("")), None);
+        assert!(!AttributeType::Boolish.has_type(&json!(null)));
+    }
+
+    #[test]
+    fn it_checks_paths_under_a_base() {
+        let etc = AttributeType::AbsolutePathUnder(PathBuf::from("/etc"));
+        assert!(etc.has_type(&json!("/etc/motd")));
+        assert!(etc.has_type(&json!("/etc//ssh/./sshd_config")));
+        assert!(etc.has_type(&json!("/etc/ssh/../motd")));
+        assert!(!etc.has_type(&json!("/etc/../root/.ssh")));
+        assert!(!etc.has_type(&json!("/etcetera")));
+        assert!(!etc.has_type(&json!("etc/motd")));
+        assert!(!etc.has_type(&json!("/../etc/motd")));
+        assert_eq!(
+            AttributeType::as_normalized_path(&json!("/etc//ssh/../motd")),
+            Some(PathBuf::from("/etc/motd"))
+        );
+    }
+
+    #[test]
+    fn it_checks_relative_paths() {
+        let relative = AttributeType::RelativePath {
+            reject_parent: false,
+        };
+        let sandboxed = AttributeType::RelativePath {
+            reject_parent: true,
+        };
+        assert!(relative.has_type(&json!("src/lib.rs")));
+        assert!(relative.has_type(&json!("../lib.rs")));
+        assert!(!relative.has_type(&json!("/src/lib.rs")));
+        assert!(!relative.has_type(&json!("")));
+        assert!(sandboxed.has_type(&json!("./src/lib.rs")));
+        assert!(sandboxed.has_type(&json!("src/..lib.rs")));
+        assert!(!sandboxed.has_type(&json!("src/../../lib.rs")));
+    }
+
+    #[test]
+    fn it_checks_json_schemas() {
+        let schema = AttributeType::JsonSchema(json!({
+            "type": "object",
+            "properties": {"port": {"type": "integer"}}
+        }));
+        assert!(schema.has_type(&json!({"port": 22})));
+        assert!(!schema.has_type(&json!({"port": "22"})));
+        assert!(!schema.has_type(&json!([])));
+    }
+
+    #[test]
+    fn it_checks_json_strings() {
+        let any = AttributeType::JsonString(None);
+        assert!(any.has_type(&json!(r#"{"port": 80}"#)));
+        assert!(any.has_type(&json!("42")));
+        assert!(!any.has_type(&json!("{port: 80}")));
+        assert!(!any.has_type(&json!({"port": 80})));
+
+        let data = AttributeType::JsonString(Some(Box::new(AttributeType::JsonSchema(
+            json!({"type": "object", "required": ["port"]}),
+        ))));
+        assert!(data.has_type(&json!(r#"{"port": 80}"#)));
+        assert!(!data.has_type(&json!(r#"{"host": "a"}"#)));
+        assert_eq!(
+            AttributeType::as_json_string(&json!("[1, 2]")),
+            Some(json!([1, 2]))
+        );
+    }
+
+    #[test]
+    fn it_checks_usernames() {
+        let strict = AttributeType::Username {
+            leading_digit: false,
+        };
+        let lenient = AttributeType::Username {
+            leading_digit: true,
+        };
+        assert!(strict.has_type(&json!("rudder")));
+        assert!(strict.has_type(&json!("_apt.user-1")));
+        assert!(!strict.has_type(&json!("1user")));
+        assert!(lenient.has_type(&json!("1user")));
+        assert!(!lenient.has_type(&json!("-user")));
+        assert!(!lenient.has_type(&json!("us er")));
+        assert!(!lenient.has_type(&json!("")));
+        assert!(!lenient.has_type(&json!("a".repeat(33))));
+    }
+
+    #[test]
+    fn it_checks_hostnames() {
+        assert!(AttributeType::Hostname.has_type(&json!("localhost")));
+        assert!(AttributeType::Hostname.has_type(&json!("node-1.rudder.io")));
+        assert!(AttributeType::Hostname.has_type(&json!("node-1.rudder.io.")));
+        assert!(AttributeType::Hostname.has_type(&json!("a".repeat(63))));
+        assert!(!AttributeType::Hostname.has_type(&json!("a".repeat(64))));
+        assert!(!AttributeType::Hostname.has_type(&json!("-node.rudder.io")));
+        assert!(!AttributeType::Hostname.has_type(&json!("node-.rudder.io")));
+        assert!(!AttributeType::Hostname.has_type(&json!("node..rudder.io")));
+        assert!(!AttributeType::Hostname.has_type(&json!("node_1")));
+        assert!(!AttributeType::Hostname.has_type(&json!(".")));
+        assert!(!AttributeType::Hostname.has_type(&json!(["a"; 127].join(".") + ".com")));
+
+        assert!(AttributeType::Fqdn.has_type(&json!("rudder.io")));
+        assert!(AttributeType::Fqdn.has_type(&json!("node.rudder.io.")));
+        assert!(!AttributeType::Fqdn.has_type(&json!("localhost")));
+        assert!(!AttributeType::Fqdn.has_type(&json!("localhost.")));
+        assert!(!AttributeType::Fqdn.has_type(&json!("192.168.1.1")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_checks_timezones() {
+        let any = AttributeType::Timezone {
+            reject_aliases: false,
+        };
+        let canonical = AttributeType::Timezone {
+            reject_aliases: true,
+        };
+        assert!(any.has_type(&json!("Europe/Paris")));
+        assert!(canonical.has_type(&json!("Europe/Paris")));
+        assert!(any.has_type(&json!("US/Eastern")));
+        assert!(!canonical.has_type(&json!("US/Eastern")));
+        assert!(!any.has_type(&json!("Europe/Pariss")));
+        assert!(!any.has_type(&json!("tzdata.zi")));
+        assert!(!any.has_type(&json!("/etc/localtime")));
+        assert_eq!(
+            AttributeType::as_timezone(&json!("Asia/Tokyo"))
+                .map(|z| z.offset_at(std::time::UNIX_EPOCH)),
+            Some(9 * 3600)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_checks_existing_usernames() {
+        assert!(AttributeType::ExistingUsername.has_type(&json!("root")));
+        assert!(!AttributeType::ExistingUsername.has_type(&json!("no_such_user_here")));
+    }
+
+    #[test]
+    fn it_splits_shell_commands() {
+        let split = |c: &str| AttributeType::as_shell_words(&json!(c));
+        assert_eq!(
+            split(r#"echo 'hello  world' "it's \"here\"" a\ b ''"#),
+            Some(vec![
+                "echo".to_string(),
+                "hello  world".to_string(),
+                "it's \"here\"".to_string(),
+                "a b".to_string(),
+                "".to_string()
+            ])
+        );
+        assert_eq!(
+            split(r#"grep "\d" x"#),
+            Some(vec!["grep".to_string(), "\\d".to_string(), "x".to_string()])
+        );
+        assert_eq!(split("echo 'oops"), None);
+        assert_eq!(split(r#"echo "oops"#), None);
+        assert_eq!(split("echo oops\\"), None);
+        assert!(AttributeType::ShellCommand.has_type(&json!("ls -l")));
+        assert!(!AttributeType::ShellCommand.has_type(&json!("  ")));
+        assert!(!AttributeType::ShellCommand.has_type(&json!("echo 'oops")));
+    }
+
+    #[test]
+    fn it_parses_key_value_lists() {
+        let pairs = |l: &str| AttributeType::as_key_value_list(&json!(l));
+        assert_eq!(
+            pairs("env=prod, team = web ,query=a=b,empty="),
+            Some(BTreeMap::from([
+                ("empty".to_string(), "".to_string()),
+                ("env".to_string(), "prod".to_string()),
+                ("query".to_string(), "a=b".to_string()),
+                ("team".to_string(), "web".to_string()),
+            ]))
+        );
+        assert_eq!(pairs(" "), Some(BTreeMap::new()));
+        assert_eq!(pairs("env=prod,env=dev"), None);
+        assert_eq!(pairs("=prod"), None);
+        assert_eq!(pairs("env=prod,"), None);
+        assert_eq!(pairs("env"), None);
+        assert!(!AttributeType::KeyValueList.has_type(&json!({"env": "prod"})));
+    }
+
+    #[test]
+    fn it_parses_durations() {
+        let duration = |v: Value| AttributeType::as_duration(&v);
+        assert_eq!(duration(json!(90)), Some(Duration::from_secs(90)));
+        assert_eq!(duration(json!("90")), Some(Duration::from_secs(90)));
+        assert_eq!(duration(json!("1h30m")), Some(Duration::from_secs(5400)));
+        assert_eq!(duration(json!("2d")), Some(Duration::from_secs(172_800)));
+        assert_eq!(
+            duration(json!("1s500ms")),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(duration(json!("")), None);
+        assert_eq!(duration(json!("h")), None);
+        assert_eq!(duration(json!("10w")), None);
+        assert_eq!(duration(json!(-1)), None);
+        let timeout = AttributeType::DurationRange {
+            max: Duration::from_secs(3600),
+        };
+        assert!(timeout.has_type(&json!("1h")));
+        assert!(!timeout.has_type(&json!("1h1s")));
+        assert!(!timeout.has_type(&json!("forever")));
+    }
+
+    #[test]
+    fn it_checks_env_var_names() {
+        assert!(AttributeType::EnvVarName.has_type(&json!("PATH")));
+        assert!(AttributeType::EnvVarName.has_type(&json!("_http_proxy2")));
+        assert!(!AttributeType::EnvVarName.has_type(&json!("2FA")));
+        assert!(!AttributeType::EnvVarName.has_type(&json!("KEY=VALUE")));
+        assert!(!AttributeType::EnvVarName.has_type(&json!("MY-VAR")));
+        assert!(!AttributeType::EnvVarName.has_type(&json!("")));
+    }
+
+    #[test]
+    fn it_checks_service_names() {
+        assert!(AttributeType::ServiceName.has_type(&json!("nginx")));
+        assert!(AttributeType::ServiceName.has_type(&json!("nginx.service")));
+        assert!(AttributeType::ServiceName.has_type(&json!("getty@tty1.service")));
+        assert!(AttributeType::ServiceName.has_type(&json!("systemd-journald.socket")));
+        assert!(AttributeType::ServiceName.has_type(&json!("container-getty@.service")));
+        assert!(!AttributeType::ServiceName.has_type(&json!(".service")));
+        assert!(!AttributeType::ServiceName.has_type(&json!("@tty1.service")));
+        assert!(!AttributeType::ServiceName.has_type(&json!("a@b@c")));
+        assert!(!AttributeType::ServiceName.has_type(&json!("my service")));
+        assert!(!AttributeType::ServiceName.has_type(&json!("a".repeat(256))));
+        assert!(!AttributeType::ServiceName.has_type(&json!("--all")));
+        assert!(!AttributeType::ServiceName.has_type(&json!("-H")));
+    }
+
+    #[test]
+    fn it_separates_units_from_systemctl_options() {
+        let command = unit_state_command("nginx.service");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(&args[args.len() - 2..], ["--", "nginx.service"]);
+    }
+
+    #[test]
+    fn it_checks_groupnames() {
+        let strict = AttributeType::Groupname {
+            leading_digit: false,
+        };
+        assert!(strict.has_type(&json!("wheel")));
+        assert!(!strict.has_type(&json!("1group")));
+        assert!(!strict.has_type(&json!("-group")));
+    }
+
+    // Other systems may not have a root group
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_checks_existing_groupnames() {
+        assert!(AttributeType::ExistingGroupname.has_type(&json!("root")));
+        assert!(!AttributeType::ExistingGroupname.has_type(&json!("no_such_group_here")));
     }
 }
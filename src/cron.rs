@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Cron schedules
+//!
+//! Follows the Vixie cron syntax of crontab(5), with an optional leading seconds field.
+//! Schedules are evaluated in UTC.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Error};
+use chrono::{DateTime, Datelike};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Parsed cron schedule
+///
+/// Each field is stored as a bit set of the matching values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Both day fields are restricted, so matching any of them is enough
+    any_day: bool,
+}
+
+impl CronSchedule {
+    /// Parse a schedule with 5 fields, or 6 with a leading seconds field if `seconds` is set
+    pub fn parse(spec: &str, seconds: bool) -> Result<Self, Error> {
+        let spec = spec.trim();
+        let expanded = match spec {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            m if m.starts_with('@') => bail!("Unsupported cron macro '{}'", m),
+            s => s,
+        };
+        let mut fields: Vec<&str> = expanded.split_whitespace().collect();
+        match fields.len() {
+            5 => fields.insert(0, "0"),
+            6 if seconds => (),
+            n => bail!("Invalid cron schedule '{}': unexpected {} fields", spec, n),
+        }
+        let mut weekdays = parse_field(fields[5], 0, 7, &WEEKDAYS)?;
+        // Both 0 and 7 are Sunday
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            seconds: parse_field(fields[0], 0, 59, &[])?,
+            minutes: parse_field(fields[1], 0, 59, &[])?,
+            hours: parse_field(fields[2], 0, 23, &[])?,
+            days: parse_field(fields[3], 1, 31, &[])?,
+            months: parse_field(fields[4], 1, 12, &MONTHS)?,
+            weekdays,
+            any_day: !fields[3].starts_with('*') && !fields[5].starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, day: i64, weekday: i64) -> bool {
+        let day = has(self.days, day);
+        let weekday = has(self.weekdays, weekday);
+        if self.any_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// Next time matching the schedule, strictly after `time`
+    ///
+    /// Returns `None` for times before the Unix epoch, or when the schedule
+    /// does not match in the next ten years (like on February 30th).
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let mut t = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 + 1;
+        let limit = t + 10 * 366 * 86400;
+        while t < limit {
+            let days = t.div_euclid(86400);
+            let date = DateTime::from_timestamp(t, 0)?;
+            let (month, day) = (i64::from(date.month()), i64::from(date.day()));
+            let weekday = i64::from(date.weekday().num_days_from_sunday());
+            let seconds = t.rem_euclid(86400);
+            let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+            if !has(self.months, month) || !self.matches_day(day, weekday) {
+                t = (days + 1) * 86400;
+            } else if !has(self.hours, hour) {
+                t = days * 86400 + (hour + 1) * 3600;
+            } else if !has(self.minutes, minute) {
+                t += 60 - second;
+            } else if !has(self.seconds, second) {
+                t += 1;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(t as u64));
+            }
+        }
+        None
+    }
+}
+
+fn has(set: u64, value: i64) -> bool {
+    set & 1 << value != 0
+}
+
+/// Parse a value, as a number or a name from `names` (starting at `min`)
+fn parse_value(value: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, Error> {
+    let parsed = if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        value.parse().ok()
+    } else {
+        names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(value))
+            .map(|p| p as u32 + min)
+    };
+    match parsed {
+        Some(v) if (min..=max).contains(&v) => Ok(v),
+        _ => bail!(
+            "Invalid cron value '{}', expecting {} to {}",
+            value,
+            min,
+            max
+        ),
+    }
+}
+
+/// Parse a comma-separated list of values, ranges and steps
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, Error> {
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(s) if s > 0 && step.chars().all(|c| c.is_ascii_digit()) => (range, Some(s)),
+                _ => bail!("Invalid cron step '{}'", step),
+            },
+            None => (item, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (
+                parse_value(start, min, max, names)?,
+                parse_value(end, min, max, names)?,
+            ),
+            None => {
+                let start = parse_value(range, min, max, names)?;
+                // `5/10` means from 5 to the maximum, every 10
+                (start, if step.is_some() { max } else { start })
+            }
+        };
+        if start > end {
+            bail!("Invalid cron range '{}'", range);
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> SystemTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|d| d.and_hms_opt(hour, minute, second))
+            .unwrap()
+            .and_utc()
+            .into()
+    }
+
+    #[test]
+    fn it_parses_schedules() {
+        assert!(CronSchedule::parse("*/15 0-6,22 1 jan-mar,dec MON", false).is_ok());
+        assert!(CronSchedule::parse("0 0 * * 7", false).is_ok());
+        assert!(CronSchedule::parse("30 0 0 * * *", true).is_ok());
+        assert!(CronSchedule::parse("@weekly", true).is_ok());
+
+        assert!(CronSchedule::parse("30 0 0 * * *", false).is_err());
+        assert!(CronSchedule::parse("0 0 * *", true).is_err());
+        assert!(CronSchedule::parse("0 24 * * *", false).is_err());
+        assert!(CronSchedule::parse("0 0 0 * *", false).is_err());
+        assert!(CronSchedule::parse("0 0 * 13 *", false).is_err());
+        assert!(CronSchedule::parse("0 0 * * 8", false).is_err());
+        assert!(CronSchedule::parse("5-1 * * * *", false).is_err());
+        assert!(CronSchedule::parse("*/0 * * * *", false).is_err());
+        assert!(CronSchedule::parse("+5 * * * *", false).is_err());
+        assert!(CronSchedule::parse("@reboot", false).is_err());
+    }
+
+    #[test]
+    fn it_computes_next_fire_times() {
+        let daily = CronSchedule::parse("@daily", false).unwrap();
+        assert_eq!(
+            daily.next_after(at(2021, 12, 31, 12, 0, 0)),
+            Some(at(2022, 1, 1, 0, 0, 0))
+        );
+        assert_eq!(
+            daily.next_after(at(2022, 1, 1, 0, 0, 0)),
+            Some(at(2022, 1, 2, 0, 0, 0))
+        );
+
+        // 2021-06-01 is a Tuesday
+        let weekdays = CronSchedule::parse("30 9 * * mon-fri", false).unwrap();
+        assert_eq!(
+            weekdays.next_after(at(2021, 6, 4, 10, 0, 0)),
+            Some(at(2021, 6, 7, 9, 30, 0))
+        );
+
+        // Restricted day of month and day of week match any of them
+        let either = CronSchedule::parse("0 0 13 * fri", false).unwrap();
+        assert_eq!(
+            either.next_after(at(2021, 6, 1, 0, 0, 0)),
+            Some(at(2021, 6, 4, 0, 0, 0))
+        );
+        assert_eq!(
+            either.next_after(at(2021, 6, 11, 0, 0, 0)),
+            Some(at(2021, 6, 13, 0, 0, 0))
+        );
+
+        let seconds = CronSchedule::parse("*/20 * * * * *", true).unwrap();
+        assert_eq!(
+            seconds.next_after(at(2021, 6, 1, 0, 0, 45)),
+            Some(at(2021, 6, 1, 0, 1, 0))
+        );
+
+        let leap = CronSchedule::parse("0 0 29 feb *", false).unwrap();
+        assert_eq!(
+            leap.next_after(at(2021, 1, 1, 0, 0, 0)),
+            Some(at(2024, 2, 29, 0, 0, 0))
+        );
+        let never = CronSchedule::parse("0 0 30 feb *", false).unwrap();
+        assert_eq!(never.next_after(at(2021, 1, 1, 0, 0, 0)), None);
+    }
+}
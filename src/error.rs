@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Errors stopping the executor
+
+use std::{error, fmt, io};
+
+/// Error ending a promise module run
+///
+/// Promise failures are reported to the agent in responses, these
+/// errors stop the module instead.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExecutorError {
+    /// Reading from the agent or writing to it failed
+    Io(io::Error),
+    /// The agent header is invalid or incompatible
+    Handshake(String),
+    /// The agent sent something that does not follow the protocol
+    Protocol(String),
+    /// The promise type failed to initialize and init failures are not reported
+    Init(String),
+    /// Error while handling a request, with the request
+    Request {
+        request: String,
+        source: Box<ExecutorError>,
+    },
+}
+
+impl ExecutorError {
+    /// Underlying error, without the request context
+    pub fn root(&self) -> &ExecutorError {
+        match self {
+            ExecutorError::Request { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// Request being handled when the error occurred, if any
+    pub fn request(&self) -> Option<&str> {
+        match self {
+            ExecutorError::Request { request, .. } => Some(request),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn while_handling(self, request: Option<String>) -> Self {
+        match request {
+            Some(request) => ExecutorError::Request {
+                request,
+                source: Box::new(self),
+            },
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutorError::Io(e) => write!(f, "{}", e),
+            ExecutorError::Handshake(e) | ExecutorError::Protocol(e) | ExecutorError::Init(e) => {
+                write!(f, "{}", e)
+            }
+            ExecutorError::Request { request, .. } => write!(f, "while handling {}", request),
+        }
+    }
+}
+
+impl error::Error for ExecutorError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ExecutorError::Io(e) => Some(e),
+            ExecutorError::Request { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ExecutorError {
+    fn from(e: io::Error) -> Self {
+        ExecutorError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_the_root_cause() {
+        let error = ExecutorError::Protocol("Expecting two empty lines".to_string())
+            .while_handling(Some("{}".to_string()));
+        assert_eq!(error.to_string(), "while handling {}");
+        assert_eq!(error.request(), Some("{}"));
+        assert!(matches!(error.root(), ExecutorError::Protocol(_)));
+        assert_eq!(
+            error::Error::source(&error).unwrap().to_string(),
+            "Expecting two empty lines"
+        );
+    }
+}
@@ -2,40 +2,200 @@
 // SPDX-FileCopyrightText: 2021 Normation SAS
 
 use std::{
-    io,
-    io::{BufRead, Lines, Write},
+    collections::HashMap,
+    fmt, fs, io,
+    io::{BufRead, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
 
-use anyhow::{bail, Error};
 use serde::Serialize;
 use serde_json::{Map, Value};
 
 use crate::{
-    attribute::AttributeType,
-    header::Header,
-    log::set_max_level,
+    asynchronous::Blocking,
+    attribute::{AttributeType, Attributes},
+    critical, debug, error,
+    error::ExecutorError,
+    header::{Capabilities, Header, InitSettings},
+    info,
+    log::{
+        flush_repeated, max_level, set_deduplication, set_max_level, set_target_levels, Level,
+        LevelFilter, Progress,
+    },
+    notice,
+    planned::{Planned, PlannedPromiseType},
+    platform::Platform,
     protocol::{
-        EvaluateOutcome, EvaluateRequest, EvaluateResponse, ProtocolResult, TerminateRequest,
-        TerminateResponse, ValidateRequest, ValidateResponse,
+        ActionPolicy, ApplyResult, Change, CheckResult, Class, EvaluateOutcome, EvaluateRequest,
+        EvaluateResponse, ProtocolOutcome, ProtocolResult, TerminateRequest, TerminateResponse,
+        ValidateOutcome, ValidateRequest, ValidateResponse, ValidateResult,
     },
-    PromiseType,
+    resource::ResourceKind,
+    schema, verbose, warning, AsyncPromiseType, PromiseType,
 };
 
+/// Maximum edit distance to suggest a known attribute for an unexpected one
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+/// Default maximum size of a line sent by the agent
+const DEFAULT_MAX_LINE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Default parent of the modules' state directories, CFEngine's state directory
+const DEFAULT_STATE_DIR: &str = "/var/cfengine/state";
+
+/// Edit distance between two strings
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Tracks the nesting of a JSON value read in several parts
+#[derive(Debug, Default)]
+struct JsonNesting {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonNesting {
+    fn feed(&mut self, part: &str) {
+        for c in part.chars() {
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => self.in_string = false,
+                    _ => (),
+                }
+            } else {
+                match c {
+                    '"' => self.in_string = true,
+                    '{' | '[' => self.depth += 1,
+                    '}' | ']' => self.depth = self.depth.saturating_sub(1),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Whether the value is not complete yet
+    fn is_open(&self) -> bool {
+        self.depth > 0 || self.in_string
+    }
+}
+
+/// Evaluation outcomes counters
+#[derive(Debug, Default, PartialEq, Clone, Serialize)]
+struct Summary {
+    kept: usize,
+    repaired: usize,
+    not_kept: usize,
+    error: usize,
+    /// Evaluated promisers, in evaluation order
+    promises: Vec<PromiseSummary>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct PromiseSummary {
+    promiser: String,
+    result: EvaluateOutcome,
+}
+
+impl Summary {
+    fn add(&mut self, promiser: &str, outcome: EvaluateOutcome) {
+        match outcome {
+            EvaluateOutcome::Kept => self.kept += 1,
+            EvaluateOutcome::Repaired => self.repaired += 1,
+            EvaluateOutcome::NotKept => self.not_kept += 1,
+            EvaluateOutcome::Error => self.error += 1,
+        }
+        self.promises.push(PromiseSummary {
+            promiser: promiser.to_string(),
+            result: outcome,
+        });
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kept={} repaired={} not_kept={} error={}",
+            self.kept, self.repaired, self.not_kept, self.error
+        )
+    }
+}
+
+/// Custom check of an attribute value, returning the problem description
+type AttributeValidator = Box<dyn Fn(&Value) -> Result<(), String>>;
+
 /// Promise executor
 ///
 /// Handles communication with the CFEngine agent using the custom promise
 /// JSON protocol on stdin/stdout.
-#[derive(Default)]
 pub struct Executor {
     /// Part of the executor as it is not a decision that belongs to the promise itself
     ignore_unknown_attributes: bool,
+    /// Log a summary of evaluation outcomes on termination
+    emit_summary: bool,
+    /// Write the evaluation outcomes as JSON on termination
+    summary_file: Option<PathBuf>,
+    /// Maximum size of a line sent by the agent
+    max_line_bytes: usize,
+    /// Parent of the modules' state directories
+    state_dir: PathBuf,
+    /// Send back the attributes in responses
+    echo_attributes: bool,
+    /// Only send back the declared attributes
+    echo_declared_attributes_only: bool,
+    /// Maximum size of an echoed attribute value
+    max_echoed_value_bytes: Option<usize>,
+    /// Never apply changes, whatever the action policy
+    warn_only: bool,
+    /// Level of not kept check results in audit mode
+    audit_not_kept_level: Level,
+    /// Answer requests with errors instead of exiting when init fails
+    report_init_failure: bool,
+    /// Initialize before the first request
+    eager_init: bool,
+    /// Define conventional result classes
+    auto_classes: bool,
+    /// Write each response with a single write call
+    atomic_writes: bool,
+    /// Log the effective attributes at verbose level when validating
+    log_effective_attributes: bool,
+    /// Log levels overriding the one requested by the agent, by target
+    target_levels: Vec<(String, LevelFilter)>,
+    /// Identical consecutive logs written before collapsing, with the series maximum duration
+    log_deduplication: Option<(usize, Duration)>,
+    /// Custom checks run after the attribute type checks, by attribute name
+    attribute_validators: Vec<(String, AttributeValidator)>,
+    /// Turn panics in promise type methods into errors
+    catch_panics: bool,
     // /// Where to store temporary files for the promise
     // temporary_dir: PathBuf,
     // /// Unique node identifier
     // node_id: String,
 }
 
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Executor {
     /// Create an executor
     ///
@@ -43,6 +203,24 @@ impl Executor {
     pub fn new() -> Self {
         Self {
             ignore_unknown_attributes: false,
+            emit_summary: false,
+            summary_file: None,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            state_dir: PathBuf::from(DEFAULT_STATE_DIR),
+            echo_attributes: true,
+            echo_declared_attributes_only: false,
+            max_echoed_value_bytes: None,
+            warn_only: false,
+            audit_not_kept_level: Level::Error,
+            report_init_failure: false,
+            eager_init: false,
+            auto_classes: false,
+            atomic_writes: false,
+            log_effective_attributes: false,
+            target_levels: vec![],
+            log_deduplication: None,
+            attribute_validators: vec![],
+            catch_panics: true,
         }
     }
 
@@ -52,6 +230,170 @@ impl Executor {
         self
     }
 
+    /// Log a summary of evaluation outcomes at notice level on termination
+    pub fn emit_summary(mut self, emit_summary: bool) -> Self {
+        self.emit_summary = emit_summary;
+        self
+    }
+
+    /// Write the evaluation outcomes of all promisers as JSON to a file on termination
+    ///
+    /// Write failures are logged as errors. Disabled by default.
+    pub fn summary_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.summary_file = Some(path.into());
+        self
+    }
+
+    /// Maximum size of a line sent by the agent, in bytes
+    ///
+    /// Longer lines make the executor fail instead of being read into memory.
+    /// Defaults to 4 MiB.
+    pub fn max_line_bytes(mut self, max_line_bytes: usize) -> Self {
+        self.max_line_bytes = max_line_bytes;
+        self
+    }
+
+    /// Parent of the modules' state directories
+    ///
+    /// Each module gets a directory named after it, passed in `InitSettings`.
+    /// Defaults to `/var/cfengine/state`, as the agent does not provide it.
+    pub fn state_dir<P: Into<PathBuf>>(mut self, state_dir: P) -> Self {
+        self.state_dir = state_dir.into();
+        self
+    }
+
+    /// Send back the promise attributes in responses
+    ///
+    /// The agent does not use them, disabling it saves IO with large attributes.
+    /// Enabled by default.
+    pub fn echo_attributes(mut self, echo_attributes: bool) -> Self {
+        self.echo_attributes = echo_attributes;
+        self
+    }
+
+    /// Only send back the declared attributes in responses
+    ///
+    /// Unknown attributes allowed by `ignore_unknown_attributes` are not echoed.
+    /// Aliases and deprecated attributes are. Disabled by default.
+    pub fn echo_declared_attributes_only(mut self, echo_declared_attributes_only: bool) -> Self {
+        self.echo_declared_attributes_only = echo_declared_attributes_only;
+        self
+    }
+
+    /// Maximum size of echoed attribute values, in bytes of JSON
+    ///
+    /// Larger values are replaced by a placeholder string in responses.
+    /// Not limited by default.
+    pub fn max_echoed_value_bytes(mut self, max_echoed_value_bytes: usize) -> Self {
+        self.max_echoed_value_bytes = Some(max_echoed_value_bytes);
+        self
+    }
+
+    /// Only check promises, as if their action policy was `warn`
+    ///
+    /// Overrides the action policy of every request, and the default one of the promise type.
+    pub fn warn_only(mut self, warn_only: bool) -> Self {
+        self.warn_only = warn_only;
+        self
+    }
+
+    /// Log level of not kept promises in audit mode
+    ///
+    /// Allows reporting drift as warnings or notices, without changing the outcome.
+    /// Defaults to `Level::Error`.
+    pub fn audit_not_kept_level(mut self, audit_not_kept_level: Level) -> Self {
+        self.audit_not_kept_level = audit_not_kept_level;
+        self
+    }
+
+    /// Keep running when the promise type initialization fails
+    ///
+    /// Validate and evaluate requests then fail with an error outcome and the init failure
+    /// message, so the agent attributes it to the promises instead of a module crash.
+    /// By default, the executor exits with an error.
+    pub fn report_init_failure(mut self, report_init_failure: bool) -> Self {
+        self.report_init_failure = report_init_failure;
+        self
+    }
+
+    /// Initialize the promise type right after the header exchange
+    ///
+    /// The executor sends its header, then runs `init` before reading the first request,
+    /// so setup failures surface immediately. By default, `init` runs when the first request
+    /// is received, to avoid expensive setups when there is nothing to do.
+    pub fn eager_init(mut self, eager_init: bool) -> Self {
+        self.eager_init = eager_init;
+        self
+    }
+
+    /// Define result classes named after the promiser and the outcome
+    ///
+    /// Like `_etc_motd_kept` or `_etc_motd_repaired` for `/etc/motd`, with the promiser
+    /// canonified. Outcome suffixes are `kept`, `repaired`, `not_kept` and `error`.
+    pub fn auto_classes(mut self, auto_classes: bool) -> Self {
+        self.auto_classes = auto_classes;
+        self
+    }
+
+    /// Write each response, with its terminating empty line, in a single `write_all` call
+    ///
+    /// Reduces the risk of torn output when stdout is shared. Responses are flushed
+    /// immediately in both cases.
+    pub fn atomic_writes(mut self, atomic_writes: bool) -> Self {
+        self.atomic_writes = atomic_writes;
+        self
+    }
+
+    /// Log the attributes passed to `validate` at verbose level
+    ///
+    /// They include renamed aliases and deprecated attributes and default values, with secrets redacted.
+    /// Defaults to `false`.
+    pub fn log_effective_attributes(mut self, log_effective_attributes: bool) -> Self {
+        self.log_effective_attributes = log_effective_attributes;
+        self
+    }
+
+    /// Override the log level requested by the agent for a log target
+    ///
+    /// Targets are module paths unless given in the log macros, like `my_module::http`,
+    /// and the level also applies to their submodules. The most specific target wins.
+    pub fn log_target_level(mut self, target: &str, level: LevelFilter) -> Self {
+        self.target_levels.push((target.to_string(), level));
+        self
+    }
+
+    /// Collapse identical consecutive logs
+    ///
+    /// After `threshold` occurrences, identical logs are replaced by a single
+    /// "(repeated N times)" log, written with the next different log or response. Series
+    /// longer than `window` start over. Disabled by default.
+    pub fn deduplicate_logs(mut self, threshold: usize, window: Duration) -> Self {
+        self.log_deduplication = Some((threshold, window));
+        self
+    }
+
+    /// Add a custom check on an attribute, for constraints not covered by `AttributeType`
+    ///
+    /// It is run when the attribute is declared, present and has the declared type.
+    /// Errors make the promise invalid, with the returned message.
+    pub fn add_attribute_validator<F>(mut self, name: &str, validator: F) -> Self
+    where
+        F: Fn(&Value) -> Result<(), String> + 'static,
+    {
+        self.attribute_validators
+            .push((name.to_string(), Box::new(validator)));
+        self
+    }
+
+    /// Report panics in validation and evaluation as errors, and keep serving requests
+    ///
+    /// Panic messages are logged at critical level. Disable to let panics
+    /// propagate while developing. Defaults to `true`.
+    pub fn catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
     /// Returns the output that would have been sent given provided input
     ///
     /// Useful for testing
@@ -59,18 +401,67 @@ impl Executor {
         &self,
         promise_type: T,
         input: &str,
-    ) -> Result<String, Error> {
+    ) -> Result<String, ExecutorError> {
         let mut output = Vec::new();
         let mut error = Vec::new();
 
         self.run_type(promise_type, input.as_bytes(), &mut output, &mut error)?;
 
-        let output = std::str::from_utf8(&output)?.to_string();
+        let output = String::from_utf8(output)
+            .map_err(|e| ExecutorError::Protocol(format!("Invalid output: {}", e)))?;
         Ok(output)
     }
 
+    /// Returns the validation outcome for a promise, including attributes checks
+    ///
+    /// Useful for testing
+    pub fn validate_once<T: PromiseType>(
+        &self,
+        promise_type: &T,
+        promiser: &str,
+        attributes: &Attributes,
+    ) -> ValidateOutcome {
+        self.validate(promise_type, promiser, attributes)
+    }
+
+    /// Returns the evaluation outcome for a promise, making changes if needed
+    ///
+    /// Assumes validation has already been done.
+    ///
+    /// Useful for testing
+    pub fn evaluate_once<T: PromiseType>(
+        &self,
+        promise_type: &mut T,
+        promiser: &str,
+        attributes: &Attributes,
+    ) -> EvaluateOutcome {
+        let (outcome, changes) = self.evaluate(promise_type, promiser, attributes);
+        Self::log_changes(&changes);
+        outcome
+    }
+
+    /// Returns the evaluation outcomes for several promises, applying changes in one batch
+    ///
+    /// The agent sends requests one at a time, so the executor loop only makes
+    /// batches of one promise. Assumes validation has already been done.
+    ///
+    /// Useful for testing
+    pub fn evaluate_batch_once<T: PromiseType>(
+        &self,
+        promise_type: &mut T,
+        promises: &[(String, Attributes)],
+    ) -> Vec<EvaluateOutcome> {
+        self.evaluate_batch(promise_type, promises)
+            .into_iter()
+            .map(|(outcome, changes)| {
+                Self::log_changes(&changes);
+                outcome
+            })
+            .collect()
+    }
+
     /// Runs a promise type for the agent, using stdio
-    pub fn run<T: PromiseType>(&self, promise_type: T) -> Result<(), Error> {
+    pub fn run<T: PromiseType>(&self, promise_type: T) -> Result<(), ExecutorError> {
         let stdin = io::stdin();
         let stdout = io::stdout();
         let stderr = io::stderr();
@@ -82,60 +473,229 @@ impl Executor {
         self.run_type(promise_type, input, output, error)
     }
 
+    /// Runs an async promise type for the agent, using stdio
+    ///
+    /// Each method's future is run to completion before handling the next request.
+    /// With the `tokio` feature, they run on a current-thread runtime, so this must not
+    /// be called from within a tokio runtime.
+    pub fn run_async<T: AsyncPromiseType>(&self, promise_type: T) -> Result<(), ExecutorError> {
+        self.run(Blocking::new(promise_type))
+    }
+
+    /// Returns the output that would have been sent given provided input
+    /// to an async promise type
+    ///
+    /// Useful for testing
+    pub fn run_async_with_input<T: AsyncPromiseType>(
+        &self,
+        promise_type: T,
+        input: &str,
+    ) -> Result<String, ExecutorError> {
+        self.run_with_input(Blocking::new(promise_type), input)
+    }
+
+    /// Runs a planned promise type for the agent, using stdio
+    ///
+    /// The plan returned by each check is passed to the following apply.
+    pub fn run_planned<T: PlannedPromiseType>(&self, promise_type: T) -> Result<(), ExecutorError> {
+        self.run(Planned::new(promise_type))
+    }
+
+    /// Returns the output that would have been sent given provided input
+    /// to a planned promise type
+    ///
+    /// Useful for testing
+    pub fn run_planned_with_input<T: PlannedPromiseType>(
+        &self,
+        promise_type: T,
+        input: &str,
+    ) -> Result<String, ExecutorError> {
+        self.run_with_input(Planned::new(promise_type), input)
+    }
+
+    /// Read a single line, without its line ending, failing if it exceeds `max_line_bytes`
+    fn read_single_line<B: BufRead>(&self, input: &mut B) -> Result<String, ExecutorError> {
+        let mut line = String::new();
+        // Allow the line ending after the maximum size
+        let read = input
+            .take(self.max_line_bytes as u64 + 1)
+            .read_line(&mut line)?;
+        if read == 0 {
+            return Err(ExecutorError::Protocol(
+                "Unexpected end of input".to_string(),
+            ));
+        }
+        if !line.ends_with('\n') && read > self.max_line_bytes {
+            return Err(ExecutorError::Protocol(format!(
+                "Line exceeds maximum size of {} bytes",
+                self.max_line_bytes
+            )));
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(line)
+    }
+
     /// Read a line followed by two empty lines
-    fn read_line<B: BufRead>(input: &mut Lines<B>) -> Result<String, Error> {
-        let line = input.next().unwrap()?;
+    ///
+    /// JSON values spanning several lines, like pretty-printed requests, are read until
+    /// complete, within the `max_line_bytes` limit.
+    fn read_line<B: BufRead>(&self, input: &mut B) -> Result<String, ExecutorError> {
+        let mut line = self.read_single_line(input)?;
+        if line.trim_start().starts_with(['{', '[']) {
+            let mut nesting = JsonNesting::default();
+            nesting.feed(&line);
+            while nesting.is_open() {
+                let next = self.read_single_line(input)?;
+                if line.len() + 1 + next.len() > self.max_line_bytes {
+                    return Err(ExecutorError::Protocol(format!(
+                        "Request exceeds maximum size of {} bytes",
+                        self.max_line_bytes
+                    )));
+                }
+                nesting.feed("\n");
+                nesting.feed(&next);
+                line.push('\n');
+                line.push_str(&next);
+            }
+        }
 
         // Read exactly two empty lines
         for _n in 0..1 {
-            let empty = input.next().unwrap()?;
+            let empty = self.read_single_line(input)?;
             if !empty.is_empty() {
-                bail!("Expecting two empty lines");
+                return Err(ExecutorError::Protocol(
+                    "Expecting two empty lines".to_string(),
+                ));
             }
         }
         Ok(line)
     }
 
     /// Write lines followed by two empty lines
-    fn write_line<W: Write>(output: &mut W, line: &str) -> Result<(), Error> {
-        output.write_all(line.as_bytes())?;
-        output.write_all(b"\n\n")?;
+    fn write_line<W: Write>(&self, output: &mut W, line: &str) -> Result<(), ExecutorError> {
+        if self.atomic_writes {
+            let mut buffer = Vec::with_capacity(line.len() + 2);
+            buffer.extend_from_slice(line.as_bytes());
+            buffer.extend_from_slice(b"\n\n");
+            output.write_all(&buffer)?;
+        } else {
+            output.write_all(line.as_bytes())?;
+            output.write_all(b"\n\n")?;
+        }
         output.flush()?;
         Ok(())
     }
 
     /// Write lines followed by two empty lines
     fn write_json<W: Write, L: Write, D: Serialize>(
+        &self,
         output: &mut W,
         _error: &mut L,
         data: D,
-    ) -> Result<(), Error> {
-        let json = serde_json::to_string(&data)?;
-        Self::write_line(output, &json)
+    ) -> Result<(), ExecutorError> {
+        let json = serde_json::to_string(&data)
+            .map_err(|e| ExecutorError::Protocol(format!("Could not serialize response: {}", e)))?;
+        // Logs belong to the request being answered
+        flush_repeated();
+        self.write_line(output, &json)
     }
 
+    /// Check attributes against declared ones, reporting all problems at once
     fn check_attributes(
         &self,
         attributes: &Map<String, Value>,
         required: Vec<(String, AttributeType)>,
         optional: Vec<(String, AttributeType)>,
-    ) -> Result<(), Error> {
+        exclusive_groups: Vec<Vec<String>>,
+        required_groups: Vec<Vec<String>>,
+        conditional_requirements: Vec<(String, Value, String)>,
+    ) -> ValidateResult {
+        let mut errors = vec![];
+
         for (attr, _) in &required {
             if attributes.get(attr).is_none() {
-                bail!("Missing required attribute {}", attr);
+                errors.push(format!("Missing required attribute {}", attr));
             }
         }
         for (attr, attr_type) in required.iter().chain(optional.iter()) {
             if let Some(value) = attributes.get(attr) {
                 if !attr_type.has_type(value) {
-                    bail!(
-                        "Attribute {} should have {:?} type",
-                        attr,
-                        attr_type
-                    );
+                    errors.push(match attr_type {
+                        AttributeType::OneOf(types) => {
+                            format!("Attribute {} should have one of {:?} types", attr, types)
+                        }
+                        AttributeType::StringLength { min, max } if value.is_string() => {
+                            format!(
+                                "Attribute {} should have between {} and {} characters, got {}",
+                                attr,
+                                min,
+                                max,
+                                value.as_str().unwrap().chars().count()
+                            )
+                        }
+                        AttributeType::DurationRange { max }
+                            if AttributeType::as_duration(value).is_some() =>
+                        {
+                            format!("Attribute {} should be at most {:?}", attr, max)
+                        }
+                        AttributeType::JsonSchema(s) => {
+                            let e = schema::validate(s, value).unwrap_err();
+                            format!(
+                                "Attribute {} does not match schema at {}: {}",
+                                attr, e.path, e.message
+                            )
+                        }
+                        _ => format!("Attribute {} should have {:?} type", attr, attr_type),
+                    });
                 }
             }
         }
+        for (attr, validator) in &self.attribute_validators {
+            let declared = required
+                .iter()
+                .chain(optional.iter())
+                .find(|(a, _)| a == attr);
+            if let (Some((_, attr_type)), Some(value)) = (declared, attributes.get(attr)) {
+                if attr_type.has_type(value) {
+                    if let Err(e) = validator(value) {
+                        errors.push(format!("Attribute {} is invalid: {}", attr, e));
+                    }
+                }
+            }
+        }
+        for group in exclusive_groups {
+            let present: Vec<String> = group
+                .into_iter()
+                .filter(|a| attributes.contains_key(a))
+                .collect();
+            if present.len() > 1 {
+                errors.push(format!(
+                    "Attributes {} are mutually exclusive",
+                    present.join(", ")
+                ));
+            }
+        }
+        for group in required_groups {
+            if !group.iter().any(|a| attributes.contains_key(a)) {
+                errors.push(format!(
+                    "One of attributes {} is required",
+                    group.join(", ")
+                ));
+            }
+        }
+        for (trigger, value, attr) in conditional_requirements {
+            if attributes.get(&trigger) == Some(&value) && !attributes.contains_key(&attr) {
+                errors.push(format!(
+                    "Attribute {} is required when {} is {}",
+                    attr, trigger, value
+                ));
+            }
+        }
         if !self.ignore_unknown_attributes {
             for (key, _) in attributes {
                 if required
@@ -144,95 +704,1878 @@ impl Executor {
                     .map(|(a, _)| a)
                     .all(|a| a != key)
                 {
-                    bail!("Unexpected attribute {}", key);
+                    let suggestion = required
+                        .iter()
+                        .chain(optional.iter())
+                        .map(|(a, _)| (levenshtein(a, key), a))
+                        .filter(|(d, _)| *d <= MAX_SUGGESTION_DISTANCE)
+                        .min();
+                    errors.push(match suggestion {
+                        Some((_, a)) => {
+                            format!("Unexpected attribute {} (did you mean {}?)", key, a)
+                        }
+                        None => format!("Unexpected attribute {}", key),
+                    });
                 }
             }
         }
 
-        Ok(())
+        if errors.is_empty() {
+            ValidateResult::Valid
+        } else {
+            ValidateResult::Invalid(errors.join(", "))
+        }
+    }
+
+    /// Hide the values of secret attributes in a raw request
+    fn redact_request(line: &str, secrets: &[String]) -> String {
+        let mut request = match serde_json::from_str::<Value>(line) {
+            Ok(r) => r,
+            Err(_) => return line.to_string(),
+        };
+        match request
+            .get_mut("attributes")
+            .and_then(|a| a.as_object_mut())
+        {
+            Some(attributes) => {
+                let redacted = Attributes::from(attributes.clone()).redacted(secrets);
+                *attributes = redacted.into_inner();
+                request.to_string()
+            }
+            None => line.to_string(),
+        }
+    }
+
+    /// Names of all attributes understood by the promise type
+    fn declared_attributes<T: PromiseType>(promise: &T) -> Vec<String> {
+        promise
+            .required_attributes()
+            .into_iter()
+            .chain(promise.optional_attributes())
+            .map(|(a, _)| a)
+            .chain(
+                Self::renamed_attributes(promise)
+                    .into_iter()
+                    .map(|(a, _)| a),
+            )
+            .collect()
+    }
+
+    /// Aliases and deprecated attributes, with their canonical name
+    fn renamed_attributes<T: PromiseType>(promise: &T) -> Vec<(String, String)> {
+        let mut renames = promise.attribute_aliases();
+        renames.extend(promise.deprecated_attributes());
+        renames
+    }
+
+    /// Rename aliases and deprecated attributes to their canonical name
+    ///
+    /// Renamed attributes are kept when their canonical name is also present.
+    fn canonical_attributes(attributes: &Attributes, renames: &[(String, String)]) -> Attributes {
+        let mut canonical = attributes.clone();
+        for (old, new) in renames {
+            if !canonical.contains_key(new) {
+                if let Some(value) = canonical.remove(old) {
+                    canonical.insert(new.clone(), value);
+                }
+            }
+        }
+        canonical
+    }
+
+    /// Add the default values of missing attributes
+    fn with_defaults(attributes: &Attributes, defaults: &[(String, Value)]) -> Attributes {
+        let mut attributes = attributes.clone();
+        for (attr, value) in defaults {
+            if !attributes.contains_key(attr) {
+                attributes.insert(attr.clone(), value.clone());
+            }
+        }
+        attributes
+    }
+
+    /// Run the promiser and attributes checks and the validate step
+    fn validate<T: PromiseType>(
+        &self,
+        promise: &T,
+        promiser: &str,
+        attributes: &Attributes,
+    ) -> ValidateOutcome {
+        let outcome = promise.validate_promiser(promiser).outcome();
+        if outcome != ValidateOutcome::Valid {
+            return outcome;
+        }
+
+        let deprecated = promise.deprecated_attributes();
+        let mut conflicts = vec![];
+        for (old, new) in &deprecated {
+            if attributes.contains_key(old) {
+                if attributes.contains_key(new) {
+                    conflicts.push(format!(
+                        "Attribute {} is deprecated and conflicts with {}",
+                        old, new
+                    ));
+                } else {
+                    warning!("Attribute {} is deprecated, use {} instead", old, new);
+                }
+            }
+        }
+        let aliases = promise.attribute_aliases();
+        for (alias, name) in &aliases {
+            // Several aliases of the same attribute conflict too
+            let other = aliases
+                .iter()
+                .any(|(a, n)| n == name && a != alias && attributes.contains_key(a));
+            if attributes.contains_key(alias) && (attributes.contains_key(name) || other) {
+                conflicts.push(format!(
+                    "Attribute {} is an alias of {} and conflicts with it",
+                    alias, name
+                ));
+            }
+        }
+        if !conflicts.is_empty() {
+            return ValidateResult::Invalid(conflicts.join(", ")).outcome();
+        }
+
+        let attributes = Self::canonical_attributes(attributes, &Self::renamed_attributes(promise));
+        match self.check_attributes(
+            &attributes,
+            promise.required_attributes(),
+            promise.optional_attributes(),
+            promise.exclusive_groups(),
+            promise.required_groups(),
+            promise.conditional_requirements(),
+        ) {
+            ValidateResult::Valid => {
+                let attributes = Self::with_defaults(&attributes, &promise.attribute_defaults());
+                if self.log_effective_attributes && Level::Verbose <= max_level() {
+                    let redacted = attributes.redacted(&promise.secret_attributes());
+                    verbose!(
+                        "Effective attributes for {}: {}",
+                        promiser,
+                        serde_json::to_string(&redacted).unwrap_or_default()
+                    );
+                }
+                promise.validate(promiser, &attributes)
+            }
+            invalid => invalid,
+        }
+        .outcome()
+    }
+
+    /// Run the check and apply steps, depending on the kind of resource
+    fn evaluate<T: PromiseType>(
+        &self,
+        promise: &mut T,
+        promiser: &str,
+        attributes: &Attributes,
+    ) -> (EvaluateOutcome, Vec<Change>) {
+        let targets = promise.expand_promiser(promiser);
+        if targets.len() == 1 && targets[0] == promiser {
+            return self
+                .evaluate_batch(promise, &[(promiser.to_string(), attributes.clone())])
+                .pop()
+                .unwrap();
+        }
+        if targets.is_empty() {
+            verbose!("No targets match {}", promiser);
+        }
+
+        // Report the worst outcome of the targets
+        let batch: Vec<(String, Attributes)> = targets
+            .into_iter()
+            .map(|t| (t, attributes.clone()))
+            .collect();
+        let results = self.evaluate_batch(promise, &batch);
+        let mut outcome = EvaluateOutcome::Kept;
+        let mut changes = vec![];
+        for ((target, _), (result, target_changes)) in batch.iter().zip(results) {
+            verbose!("{} is {}", target, Self::outcome_name(result));
+            outcome = outcome.max(result);
+            changes.extend(target_changes);
+        }
+        (outcome, changes)
+    }
+
+    fn outcome_name(outcome: EvaluateOutcome) -> &'static str {
+        match outcome {
+            EvaluateOutcome::Kept => "kept",
+            EvaluateOutcome::Repaired => "repaired",
+            EvaluateOutcome::NotKept => "not_kept",
+            EvaluateOutcome::Error => "error",
+        }
+    }
+
+    /// Conventional result classes for an evaluation, if enabled
+    fn result_classes(&self, promiser: &str, outcome: EvaluateOutcome) -> Vec<Class> {
+        if !self.auto_classes {
+            return vec![];
+        }
+        vec![Class::canonify(format!(
+            "{}_{}",
+            promiser,
+            Self::outcome_name(outcome)
+        ))]
+    }
+
+    /// Log changes for agents not receiving them in responses
+    fn log_changes(changes: &[Change]) {
+        for change in changes {
+            info!("{}", change);
+        }
+    }
+
+    /// Run the check steps for all promises, then a single apply step for the ones to fix
+    fn evaluate_batch<T: PromiseType>(
+        &self,
+        promise: &mut T,
+        promises: &[(String, Attributes)],
+    ) -> Vec<(EvaluateOutcome, Vec<Change>)> {
+        let renames = Self::renamed_attributes(promise);
+        let defaults = promise.attribute_defaults();
+        let kind = promise.kind();
+        let default_policy = promise.default_action_policy();
+        let mut outcomes = Vec::with_capacity(promises.len());
+        let mut indexes = vec![];
+        let mut to_apply = vec![];
+
+        for (index, (promiser, attributes)) in promises.iter().enumerate() {
+            let attributes =
+                Self::with_defaults(&Self::canonical_attributes(attributes, &renames), &defaults);
+            let policy = if self.warn_only {
+                ActionPolicy::Warn
+            } else {
+                attributes
+                    .get("action_policy")
+                    .map(ActionPolicy::from_value)
+                    .unwrap_or(default_policy)
+            };
+            let is_check_only = policy == ActionPolicy::Warn;
+            // Not kept state won't be fixed
+            let is_audit =
+                is_check_only || policy == ActionPolicy::Nop || kind == ResourceKind::Check;
+
+            let check = match kind {
+                // Actions have no check method
+                ResourceKind::Action => CheckResult::AlwaysApply,
+                ResourceKind::State | ResourceKind::Check => promise.check(promiser, &attributes),
+            };
+            // No-op mode only reports the outcome
+            let result = check.outcome(if is_audit && policy != ActionPolicy::Nop {
+                self.audit_not_kept_level
+            } else {
+                Level::Info
+            });
+            if is_check_only && result == EvaluateOutcome::NotKept {
+                for change in promise.plan(promiser, &attributes) {
+                    notice!("{}", change);
+                }
+            }
+            outcomes.push((result, vec![]));
+            if !is_audit && result != EvaluateOutcome::Kept {
+                indexes.push(index);
+                to_apply.push((promiser.clone(), attributes));
+            }
+        }
+        if to_apply.is_empty() {
+            return outcomes;
+        }
+
+        // Make changes
+        let applied = promise.apply_batch(&to_apply, &mut Progress::new(promise.name()));
+        for (n, (promiser, attributes)) in to_apply.iter().enumerate() {
+            outcomes[indexes[n]] = match applied.get(n) {
+                // Keep the check outcome
+                Some(ApplyResult::AuditOnly) => outcomes[indexes[n]].clone(),
+                Some(apply) => {
+                    let result = apply.outcome();
+                    if let ApplyResult::PartiallyApplied(_) = apply {
+                        if promise.rollback(promiser, attributes).outcome()
+                            != ProtocolOutcome::Success
+                        {
+                            (EvaluateOutcome::Error, vec![])
+                        } else {
+                            (result, vec![])
+                        }
+                    } else {
+                        (result, apply.changes())
+                    }
+                }
+                None => {
+                    error!("Missing apply result for {}", promiser);
+                    (EvaluateOutcome::Error, vec![])
+                }
+            };
+        }
+        outcomes
     }
 
     fn run_type<T: PromiseType, R: BufRead, W: Write, L: Write>(
         &self,
         mut promise: T,
-        input: R,
+        mut input: R,
         mut output: W,
         mut logger: L,
-    ) -> Result<(), Error> {
+    ) -> Result<(), ExecutorError> {
+        // Log settings are global, reset the ones of previous executors
+        set_target_levels(self.target_levels.clone());
+        set_deduplication(self.log_deduplication);
+
         // Parse agent header
-        let mut input = input.lines();
-        let first_line = Self::read_line(&mut input)?;
-        let header = Header::from_str(&first_line)?;
-        header.compatibility()?;
+        let first_line = self.read_line(&mut input)?;
+        let header = match Header::from_str(&first_line).and_then(|h| h.compatibility().map(|_| h))
+        {
+            Ok(h) => h,
+            Err(e) => {
+                critical!("{}", e);
+                return Err(ExecutorError::Handshake(e.to_string()));
+            }
+        };
 
         // Send my header
-        let my_header =
-            Header::new(promise.name().to_string(), promise.version().to_string()).to_string();
-        Self::write_line(&mut output, &my_header)?;
+        let my_header = Header::new(
+            promise.name().to_string(),
+            promise.version().to_string(),
+            promise.capabilities(),
+        )
+        .to_string();
+        self.write_line(&mut output, &my_header)?;
 
-        let mut initialized = false;
+        for conflict in Self::conflicting_declarations(&promise) {
+            critical!("{}", conflict);
+        }
 
-        // Now we're all set up, let's run the executor main loop
-        loop {
-            let line = Self::read_line(&mut input)?;
-            let line = dbg!(line);
-            // Lazily run initializer, in case it is expensive
-            if !initialized {
-                match promise.init() {
-                    ProtocolResult::Failure(e) => {
-                        bail!("failed to initialize promise type: {}", e);
-                    }
-                    ProtocolResult::Error(e) => {
-                        bail!(
-                            "failed to initialize promise type with unexpected: {}",
-                            e
-                        );
-                    }
-                    ProtocolResult::Success => (),
-                }
-                initialized = true;
-            }
+        let mut last_request = None;
+        self.handle_requests(
+            &mut promise,
+            &header,
+            &mut input,
+            &mut output,
+            &mut logger,
+            &mut last_request,
+        )
+        .map_err(|e| e.while_handling(last_request))
+    }
+
+    /// Attributes declared both required and optional, a programming error in the promise type
+    ///
+    /// Also reports JSON schemas using unsupported keywords.
+    fn conflicting_declarations<T: PromiseType>(promise: &T) -> Vec<String> {
+        let optional = promise.optional_attributes();
+        let required = promise.required_attributes();
+        let schemas = required
+            .iter()
+            .chain(optional.iter())
+            .filter_map(|(attr, attr_type)| match attr_type {
+                AttributeType::JsonSchema(s) => schema::check_schema(s, "")
+                    .err()
+                    .map(|e| format!("Attribute {} schema is not supported: {}", attr, e.message)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        required
+            .iter()
+            .filter(|(attr, _)| optional.iter().any(|(o, _)| o == attr))
+            .map(|(attr, _)| format!("Attribute {} is declared both required and optional", attr))
+            .chain(schemas)
+            .collect()
+    }
+
+    /// Run the initializer, returning the failure message to report in responses
+    ///
+    /// Fails if init failures are not reported.
+    fn initialize<T: PromiseType>(
+        &self,
+        promise: &mut T,
+        header: &Header,
+    ) -> Result<Option<String>, ExecutorError> {
+        if !Platform::is_supported(&promise.supported_platforms()) {
+            notice!(
+                "{} is not supported on this platform, promises will be skipped",
+                promise.name()
+            );
+            return Ok(None);
+        }
+        let settings = InitSettings::new(
+            header,
+            self.state_dir.join(promise.name()),
+            promise.capabilities(),
+        );
+        let result = self
+            .guard(|| promise.init_with_settings(&settings))
+            .unwrap_or_else(|| ProtocolResult::Error("panic".to_string()));
+        let failure = match result {
+            ProtocolResult::Failure(e) => format!("failed to initialize promise type: {}", e),
+            ProtocolResult::Error(e) => {
+                format!("failed to initialize promise type with unexpected: {}", e)
+            }
+            ProtocolResult::Success => return Ok(None),
+        };
+        if !self.report_init_failure {
+            return Err(ExecutorError::Init(failure));
+        }
+        Ok(Some(failure))
+    }
+
+    /// Run a promise type method, logging panics at critical level if they are caught
+    ///
+    /// Returns `None` when the method panicked.
+    fn guard<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        if !self.catch_panics {
+            return Some(f());
+        }
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                let message = e
+                    .downcast_ref::<&str>()
+                    .map(|m| m.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                critical!("Promise type panicked: {}", message);
+                None
+            }
+        }
+    }
+
+    /// Identifies a promise, to reuse its validation outcome
+    fn validation_key(
+        promise_type: &str,
+        promiser: &str,
+        attributes: &Attributes,
+    ) -> (String, String, String) {
+        (
+            promise_type.to_string(),
+            promiser.to_string(),
+            serde_json::to_string(attributes).unwrap_or_default(),
+        )
+    }
+
+    /// Main loop, keeping the last request read for diagnostics
+    fn handle_requests<T: PromiseType, R: BufRead, W: Write, L: Write>(
+        &self,
+        promise: &mut T,
+        header: &Header,
+        input: &mut R,
+        output: &mut W,
+        logger: &mut L,
+        last_request: &mut Option<String>,
+    ) -> Result<(), ExecutorError> {
+        let capabilities = header.capabilities() & promise.capabilities();
+        let mut initialized = self.eager_init;
+        let mut init_failure = if self.eager_init {
+            self.initialize(promise, header)?
+        } else {
+            None
+        };
+        let mut summary = Summary::default();
+        let secrets = promise.secret_attributes();
+        // Validation outcomes of the session, reused when evaluating
+        let mut validated = HashMap::new();
+
+        // Now we're all set up, let's run the executor main loop
+        loop {
+            let line = self.read_line(input)?;
+            let request = Self::redact_request(&line, &secrets);
+            debug!("{}", request);
+            *last_request = Some(request.clone());
+            // Lazily run initializer, in case it is expensive
+            if !initialized {
+                init_failure = self.initialize(promise, header)?;
+                initialized = true;
+            }
 
             // Handle requests
-            if let Ok(req) = serde_json::from_str::<ValidateRequest>(&line) {
+            if let Ok(mut req) = serde_json::from_str::<ValidateRequest>(&line) {
                 set_max_level(req.log_level);
-                // Check parameters
-                self.check_attributes(
-                    &req.attributes,
-                    promise.required_attributes(),
-                    promise.optional_attributes(),
-                )?;
-                let result = promise.validate(&req.promiser, &req.attributes).outcome();
-                Self::write_json(
-                    &mut output,
-                    &mut logger,
-                    ValidateResponse::new(&req, result),
+                let context_set = self.guard(|| promise.set_context(&req.context())).is_some();
+                let result = match &init_failure {
+                    Some(e) => {
+                        error!("{}", e);
+                        ValidateOutcome::Error
+                    }
+                    None if !context_set => ValidateOutcome::Error,
+                    None => {
+                        let outcome = self
+                            .guard(|| self.validate(promise, &req.promiser, &req.attributes))
+                            .unwrap_or(ValidateOutcome::Error);
+                        validated.insert(
+                            Self::validation_key(&req.promise_type, &req.promiser, &req.attributes),
+                            outcome,
+                        );
+                        outcome
+                    }
+                };
+                // Echo the effective attributes
+                req.attributes =
+                    Self::with_defaults(&req.attributes, &promise.attribute_defaults());
+                let declared = self
+                    .echo_declared_attributes_only
+                    .then(|| Self::declared_attributes(promise));
+                self.write_json(
+                    output,
+                    logger,
+                    ValidateResponse::builder(&req)
+                        .outcome(result)
+                        .redact(&secrets)
+                        .only(declared.as_deref())
+                        .echo(self.echo_attributes, self.max_echoed_value_bytes)
+                        .build(),
                 )?
-            } else if let Ok(req) = serde_json::from_str::<EvaluateRequest>(&line) {
+            } else if let Ok(mut req) = serde_json::from_str::<EvaluateRequest>(&line) {
                 set_max_level(req.log_level);
-                // FIXME fix once implemented
-                let is_check_only = req.attributes.get("action_policy").is_some();
-
-                let mut result = promise
-                    .check(&req.promiser, &req.attributes)
-                    .outcome(is_check_only);
-                if !is_check_only && result != EvaluateOutcome::Kept {
-                    // Make changes
-                    result = promise.apply(&req.promiser, &req.attributes).outcome();
-                }
-                Self::write_json(
-                    &mut output,
-                    &mut logger,
-                    EvaluateResponse::new(&req, result, vec![]),
+                let context_set = self.guard(|| promise.set_context(&req.context())).is_some();
+                // Registries support platforms per promise type
+                let supported = Platform::is_supported(&promise.supported_platforms());
+                let (result, changes) = match &init_failure {
+                    Some(e) => {
+                        error!("{}", e);
+                        (EvaluateOutcome::Error, vec![])
+                    }
+                    None if !context_set => (EvaluateOutcome::Error, vec![]),
+                    None if !supported => {
+                        info!("Skipping {} on unsupported platform", req.promiser);
+                        (EvaluateOutcome::Kept, vec![])
+                    }
+                    None => {
+                        let validation = *validated
+                            .entry(Self::validation_key(
+                                &req.promise_type,
+                                &req.promiser,
+                                &req.attributes,
+                            ))
+                            .or_insert_with(|| {
+                                self.guard(|| {
+                                    self.validate(promise, &req.promiser, &req.attributes)
+                                })
+                                .unwrap_or(ValidateOutcome::Error)
+                            });
+                        if validation == ValidateOutcome::Valid {
+                            self.guard(|| self.evaluate(promise, &req.promiser, &req.attributes))
+                                .unwrap_or((EvaluateOutcome::Error, vec![]))
+                        } else {
+                            error!("Not evaluating invalid promise {}", req.promiser);
+                            (EvaluateOutcome::Error, vec![])
+                        }
+                    }
+                };
+                req.attributes =
+                    Self::with_defaults(&req.attributes, &promise.attribute_defaults());
+                let declared = self
+                    .echo_declared_attributes_only
+                    .then(|| Self::declared_attributes(promise));
+                let changes = if capabilities.contains(Capabilities::CHANGES) {
+                    changes
+                } else {
+                    Self::log_changes(&changes);
+                    vec![]
+                };
+                summary.add(&req.promiser, result);
+                self.write_json(
+                    output,
+                    logger,
+                    EvaluateResponse::builder(&req)
+                        .outcome(result)
+                        .classes(self.result_classes(&req.promiser, result))
+                        .changes(changes)
+                        .redact(&secrets)
+                        .only(declared.as_deref())
+                        .echo(self.echo_attributes, self.max_echoed_value_bytes)
+                        .build(),
                 )?
             } else if let Ok(_req) = serde_json::from_str::<TerminateRequest>(&line) {
-                let result = promise.terminate().outcome();
-                Self::write_json(&mut output, &mut logger, TerminateResponse::new(result))?;
+                if self.emit_summary {
+                    notice!("{}", summary);
+                }
+                let result = self
+                    .guard(|| promise.terminate())
+                    .unwrap_or_else(|| {
+                        ProtocolResult::Error("promise type panicked while terminating".to_string())
+                    })
+                    .outcome();
+                if let Some(path) = &self.summary_file {
+                    let written = serde_json::to_string(&summary)
+                        .map_err(io::Error::from)
+                        .and_then(|s| fs::write(path, s));
+                    if let Err(e) = written {
+                        error!("Could not write summary to {}: {}", path.display(), e);
+                    }
+                }
+                self.write_json(output, logger, TerminateResponse::new(result))?;
                 return Ok(());
             } else {
                 // Stop the program?
-                bail!("Could not parse request: {}", line);
+                return Err(ExecutorError::Protocol(format!(
+                    "Could not parse request: {}",
+                    request
+                )));
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::{
+        name,
+        protocol::{PromiseContext, Repair},
+        testing::TestSession,
+        version, Level,
+    };
+
+    struct Test {
+        kind: ResourceKind,
+        apply: ApplyResult,
+        rollback: ProtocolResult,
+    }
+
+    impl Test {
+        fn new(kind: ResourceKind) -> Self {
+            Self {
+                kind,
+                apply: ApplyResult::Repaired("fixed".to_string()),
+                rollback: ProtocolResult::Success,
+            }
+        }
+    }
+
+    impl PromiseType for Test {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn kind(&self) -> ResourceKind {
+            self.kind
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("not there".to_string())
+        }
+
+        fn apply(&mut self, _promiser: &str, _attributes: &Attributes) -> ApplyResult {
+            self.apply.clone()
+        }
+
+        fn rollback(&mut self, _promiser: &str, _attributes: &Attributes) -> ProtocolResult {
+            self.rollback.clone()
+        }
+    }
+
+    /// Attributes from a JSON object
+    fn attributes(value: Value) -> Attributes {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn evaluate(promise: Test) -> String {
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        Executor::new().run_with_input(promise, &input).unwrap()
+    }
+
+    #[test]
+    fn it_applies_state_resources() {
+        assert!(evaluate(Test::new(ResourceKind::State)).contains(r#""result":"repaired""#));
+    }
+
+    #[test]
+    fn it_never_applies_check_resources() {
+        assert!(evaluate(Test::new(ResourceKind::Check)).contains(r#""result":"not_kept""#));
+    }
+
+    #[test]
+    fn it_always_applies_action_resources() {
+        assert!(evaluate(Test::new(ResourceKind::Action)).contains(r#""result":"repaired""#));
+    }
+
+    #[test]
+    fn it_reports_all_attribute_errors() {
+        let mut attributes = Map::new();
+        attributes.insert("mode".to_string(), Value::String("0644".to_string()));
+        attributes.insert("owner".to_string(), Value::String("root".to_string()));
+        let result = Executor::new().check_attributes(
+            &attributes,
+            vec![
+                ("path".to_string(), AttributeType::AbsolutePath),
+                ("state".to_string(), AttributeType::String),
+                ("mode".to_string(), AttributeType::Integer),
+                (
+                    "owner".to_string(),
+                    AttributeType::StringLength { min: 5, max: 32 },
+                ),
+            ],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        );
+        assert_eq!(
+            result,
+            ValidateResult::Invalid(
+                "Missing required attribute path, Missing required attribute state, \
+                 Attribute mode should have Integer type, \
+                 Attribute owner should have between 5 and 32 characters, got 4"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn it_reports_duration_maximum() {
+        let mut attributes = Map::new();
+        attributes.insert("timeout".to_string(), Value::String("2h".to_string()));
+        let result = Executor::new().check_attributes(
+            &attributes,
+            vec![(
+                "timeout".to_string(),
+                AttributeType::DurationRange {
+                    max: Duration::from_secs(3600),
+                },
+            )],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        );
+        assert_eq!(
+            result,
+            ValidateResult::Invalid("Attribute timeout should be at most 3600s".to_string())
+        );
+    }
+
+    #[test]
+    fn it_runs_attribute_validators() {
+        let executor = Executor::new().add_attribute_validator("port", |v| match v.as_i64() {
+            Some(p) if p < 1024 => Err("privileged ports are not allowed".to_string()),
+            _ => Ok(()),
+        });
+        let check = |port: Value| {
+            let mut attributes = Map::new();
+            attributes.insert("port".to_string(), port);
+            executor.check_attributes(
+                &attributes,
+                vec![("port".to_string(), AttributeType::Integer)],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+            )
+        };
+        assert_eq!(check(Value::from(8080)), ValidateResult::Valid);
+        assert_eq!(
+            check(Value::from(80)),
+            ValidateResult::Invalid(
+                "Attribute port is invalid: privileged ports are not allowed".to_string()
+            )
+        );
+        // Only the type error is reported
+        assert_eq!(
+            check(Value::from("80")),
+            ValidateResult::Invalid("Attribute port should have Integer type".to_string())
+        );
+    }
+
+    #[test]
+    fn it_suggests_known_attributes() {
+        assert_eq!(levenshtein("repo", "repos"), 1);
+        assert_eq!(levenshtein("state", "sttae"), 2);
+        assert_eq!(levenshtein("", "abc"), 3);
+
+        let mut attributes = Map::new();
+        attributes.insert("repos".to_string(), Value::String("/tmp".to_string()));
+        attributes.insert("something".to_string(), Value::Bool(true));
+        let result = Executor::new().check_attributes(
+            &attributes,
+            vec![],
+            vec![("repo".to_string(), AttributeType::String)],
+            vec![],
+            vec![],
+            vec![],
+        );
+        assert_eq!(
+            result,
+            ValidateResult::Invalid(
+                "Unexpected attribute repos (did you mean repo?), Unexpected attribute something"
+                    .to_string()
+            )
+        );
+    }
+
+    struct Renamed {}
+
+    impl PromiseType for Renamed {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("repo".to_string(), AttributeType::String)]
+        }
+
+        fn deprecated_attributes(&self) -> Vec<(String, String)> {
+            vec![("repository".to_string(), "repo".to_string())]
+        }
+
+        fn validate(&self, _promiser: &str, attributes: &Attributes) -> ValidateResult {
+            match attributes.get_str("repo") {
+                Some(_) => ValidateResult::Valid,
+                None => ValidateResult::Invalid("no repo".to_string()),
+            }
+        }
+    }
+
+    struct Absolute {}
+
+    impl PromiseType for Absolute {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn validate_promiser(&self, promiser: &str) -> ValidateResult {
+            match promiser.starts_with('/') {
+                true => ValidateResult::Valid,
+                false => ValidateResult::Invalid(format!("{} is not absolute", promiser)),
+            }
+        }
+    }
+
+    #[test]
+    fn it_validates_promisers() {
+        let executor = Executor::new();
+        let attributes = Attributes::default();
+        assert_eq!(
+            executor.validate_once(&Absolute {}, "/tmp/test", &attributes),
+            ValidateOutcome::Valid
+        );
+        assert_eq!(
+            executor.validate_once(&Absolute {}, "tmp/test", &attributes),
+            ValidateOutcome::Invalid
+        );
+    }
+
+    #[test]
+    fn it_renames_deprecated_attributes() {
+        if !crate::log::compiled_in(Level::Warning) {
+            return;
+        }
+        let executor = Executor::new();
+        let mut attributes = Attributes::default();
+        attributes.insert("repository".to_string(), Value::String("/tmp".to_string()));
+        let (outcome, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            executor.validate_once(&Renamed {}, "/tmp/test", &attributes)
+        });
+        assert_eq!(outcome, ValidateOutcome::Valid);
+        assert!(logs.contains(&(
+            Level::Warning,
+            "Attribute repository is deprecated, use repo instead".to_string()
+        )));
+
+        attributes.insert("repo".to_string(), Value::String("/tmp".to_string()));
+        assert_eq!(
+            executor.validate_once(&Renamed {}, "/tmp/test", &attributes),
+            ValidateOutcome::Invalid
+        );
+    }
+
+    struct Aliased {}
+
+    impl PromiseType for Aliased {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("path".to_string(), AttributeType::AbsolutePath)]
+        }
+
+        fn attribute_aliases(&self) -> Vec<(String, String)> {
+            vec![
+                ("target".to_string(), "path".to_string()),
+                ("file".to_string(), "path".to_string()),
+            ]
+        }
+    }
+
+    #[test]
+    fn it_renames_attribute_aliases() {
+        let executor = Executor::new();
+        let mut attributes = Attributes::default();
+        attributes.insert("target".to_string(), Value::String("/tmp".to_string()));
+        let (outcome, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            executor.validate_once(&Aliased {}, "/tmp/test", &attributes)
+        });
+        assert_eq!(outcome, ValidateOutcome::Valid);
+        assert!(logs.is_empty());
+
+        attributes.insert("file".to_string(), Value::String("/tmp".to_string()));
+        assert_eq!(
+            executor.validate_once(&Aliased {}, "/tmp/test", &attributes),
+            ValidateOutcome::Invalid
+        );
+    }
+
+    #[test]
+    fn it_checks_attribute_groups() {
+        let content = || {
+            vec![
+                ("content".to_string(), AttributeType::String),
+                ("content_file".to_string(), AttributeType::AbsolutePath),
+            ]
+        };
+        let group = || vec![vec!["content".to_string(), "content_file".to_string()]];
+        let executor = Executor::new();
+
+        let mut attributes = Map::new();
+        assert_eq!(
+            executor.check_attributes(&attributes, vec![], content(), group(), group(), vec![]),
+            ValidateResult::Invalid(
+                "One of attributes content, content_file is required".to_string()
+            )
+        );
+        attributes.insert("content".to_string(), Value::String("a".to_string()));
+        assert_eq!(
+            executor.check_attributes(&attributes, vec![], content(), group(), group(), vec![]),
+            ValidateResult::Valid
+        );
+        attributes.insert(
+            "content_file".to_string(),
+            Value::String("/tmp/a".to_string()),
+        );
+        assert_eq!(
+            executor.check_attributes(&attributes, vec![], content(), group(), group(), vec![]),
+            ValidateResult::Invalid(
+                "Attributes content, content_file are mutually exclusive".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn it_checks_conditional_requirements() {
+        let optional = || {
+            vec![
+                ("auth".to_string(), AttributeType::String),
+                ("auth_token".to_string(), AttributeType::String),
+            ]
+        };
+        let conditions = || {
+            vec![(
+                "auth".to_string(),
+                Value::String("token".to_string()),
+                "auth_token".to_string(),
+            )]
+        };
+        let executor = Executor::new();
+        let check = |attributes: &Map<String, Value>| {
+            executor.check_attributes(attributes, vec![], optional(), vec![], vec![], conditions())
+        };
+
+        let mut attributes = Map::new();
+        assert_eq!(check(&attributes), ValidateResult::Valid);
+        attributes.insert("auth".to_string(), Value::String("none".to_string()));
+        assert_eq!(check(&attributes), ValidateResult::Valid);
+        attributes.insert("auth".to_string(), Value::String("token".to_string()));
+        assert_eq!(
+            check(&attributes),
+            ValidateResult::Invalid(
+                "Attribute auth_token is required when auth is \"token\"".to_string()
+            )
+        );
+        attributes.insert(
+            "auth_token".to_string(),
+            Value::String("secret".to_string()),
+        );
+        assert_eq!(check(&attributes), ValidateResult::Valid);
+    }
+
+    struct Secret {}
+
+    impl PromiseType for Secret {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![
+                ("user".to_string(), AttributeType::String),
+                ("password".to_string(), AttributeType::String),
+            ]
+        }
+
+        fn secret_attributes(&self) -> Vec<String> {
+            vec!["password".to_string()]
+        }
+    }
+
+    #[test]
+    fn it_redacts_secret_attributes() {
+        let request = r#"{"operation":"validate_promise","log_level":"info","promise_type":"test","promiser":"/tmp/test","attributes":{"user":"admin","password":"hunter2"},"filename":"/tmp/test.cf","line_number":42}"#;
+        let input = TestSession::new()
+            .validate(
+                "/tmp/test",
+                attributes(json!({"user": "admin", "password": "hunter2"})),
+            )
+            .input();
+        let output = Executor::new().run_with_input(Secret {}, &input).unwrap();
+        assert!(output.contains(r#""password":"***""#));
+        assert!(output.contains(r#""user":"admin""#));
+        assert!(!output.contains("hunter2"));
+        assert!(
+            !Executor::redact_request(request, &Secret {}.secret_attributes()).contains("hunter2")
+        );
+    }
+
+    #[test]
+    fn it_redacts_malformed_requests() {
+        // Missing promiser
+        let request = r#"{"operation":"validate_promise","log_level":"info","promise_type":"test","attributes":{"password":"hunter2"},"filename":"/tmp/test.cf","line_number":42}"#;
+        let input = format!("CFEngine 3.18.0 v1\n\n{}\n\n", request);
+        let error = Executor::new()
+            .run_with_input(Secret {}, &input)
+            .unwrap_err();
+        assert!(error
+            .root()
+            .to_string()
+            .starts_with("Could not parse request"));
+        assert!(!error.root().to_string().contains("hunter2"));
+        assert!(!error.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn it_limits_echoed_attributes() {
+        let input = TestSession::new()
+            .validate(
+                "/tmp/test",
+                attributes(json!({"user": "admin", "password": "a long password"})),
+            )
+            .input();
+        let output = Executor::new()
+            .max_echoed_value_bytes(6)
+            .run_with_input(Secret {}, &input)
+            .unwrap();
+        assert!(output.contains(r#""attributes":{"password":"***","user":"<truncated 7 bytes>"}"#));
+
+        let output = Executor::new()
+            .echo_attributes(false)
+            .run_with_input(Secret {}, &input)
+            .unwrap();
+        assert!(!output.contains("attributes"));
+        assert!(output.contains(r#""result":"valid""#));
+    }
+
+    #[test]
+    fn it_echoes_declared_attributes_only() {
+        let input = TestSession::new()
+            .validate(
+                "/tmp/test",
+                attributes(json!({"user": "admin", "color": "blue"})),
+            )
+            .input();
+        let executor = Executor::new().ignore_unknown_attributes(true);
+        let output = executor.run_with_input(Secret {}, &input).unwrap();
+        assert!(output.contains(r#""attributes":{"color":"blue","user":"admin"}"#));
+
+        let output = executor
+            .echo_declared_attributes_only(true)
+            .run_with_input(Secret {}, &input)
+            .unwrap();
+        assert!(output.contains(r#""attributes":{"user":"admin"}"#));
+        assert!(output.contains(r#""result":"valid""#));
+    }
+
+    #[test]
+    fn it_logs_incompatible_headers() {
+        let (result, logs) = crate::log::capture(|| {
+            Executor::new().run_with_input(Test::new(ResourceKind::State), "CFEngine 3.18.0 v2\n\n")
+        });
+        assert!(result.is_err());
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].0, Level::Critical);
+    }
+
+    #[test]
+    fn it_reports_the_last_request_on_errors() {
+        let error = Executor::new()
+            .run_with_input(
+                Test::new(ResourceKind::State),
+                "CFEngine 3.18.0 v1\n\n{\"operation\": \"unknown\"}\n\n",
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"while handling {"operation": "unknown"}"#
+        );
+        assert!(matches!(error.root(), ExecutorError::Protocol(_)));
+    }
+
+    struct Files {}
+
+    impl PromiseType for Files {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+            match promiser {
+                "/tmp/*.conf" => vec!["/tmp/a.conf".to_string(), "/tmp/b.conf".to_string()],
+                "/tmp/*.none" => vec![],
+                p => vec![p.to_string()],
+            }
+        }
+
+        fn check(&mut self, promiser: &str, _attributes: &Attributes) -> CheckResult {
+            if promiser == "/tmp/a.conf" {
+                CheckResult::Kept
+            } else {
+                CheckResult::NotKept(format!("{} differs", promiser))
+            }
+        }
+
+        fn apply(&mut self, promiser: &str, _attributes: &Attributes) -> ApplyResult {
+            ApplyResult::Repaired(format!("fixed {}", promiser))
+        }
+    }
+
+    #[test]
+    fn it_evaluates_expanded_promisers() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let (outcomes, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            let mut promise = Files {};
+            let executor = Executor::new();
+            ["/tmp/*.conf", "/tmp/*.none", "/tmp/a.conf"]
+                .iter()
+                .map(|p| executor.evaluate_once(&mut promise, p, &Attributes::default()))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            outcomes,
+            vec![
+                EvaluateOutcome::Repaired,
+                EvaluateOutcome::Kept,
+                EvaluateOutcome::Kept
+            ]
+        );
+        assert!(logs.contains(&(Level::Info, "fixed /tmp/b.conf".to_string())));
+        assert!(!logs.iter().any(|(_, m)| m.contains("/tmp/a.conf")));
+    }
+
+    struct Download {}
+
+    impl PromiseType for Download {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn apply_with_progress(
+            &mut self,
+            _promiser: &str,
+            _attributes: &Attributes,
+            progress: &mut Progress<'_>,
+        ) -> ApplyResult {
+            progress.report("50%");
+            progress.report("100%");
+            ApplyResult::Repaired("downloaded".to_string())
+        }
+    }
+
+    #[test]
+    fn it_reports_apply_progress() {
+        if !crate::log::compiled_in(Level::Verbose) {
+            return;
+        }
+        let input = TestSession::new()
+            .log_level(LevelFilter::Verbose)
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let (output, logs) =
+            crate::log::capture(|| Executor::new().run_with_input(Download {}, &input).unwrap());
+        assert!(output.contains(r#""result":"repaired""#));
+        let progress: Vec<_> = logs.iter().filter(|(_, m)| m.ends_with('%')).collect();
+        assert_eq!(
+            progress,
+            vec![
+                &(Level::Verbose, "50%".to_string()),
+                &(Level::Verbose, "100%".to_string())
+            ]
+        );
+    }
+
+    struct Broken {}
+
+    impl PromiseType for Broken {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn init(&mut self) -> ProtocolResult {
+            ProtocolResult::Failure("missing git binary".to_string())
+        }
+    }
+
+    #[test]
+    fn it_reports_init_failures() {
+        if !crate::log::compiled_in(Level::Error) {
+            return;
+        }
+        let input = TestSession::new()
+            .validate("/tmp/test", Attributes::default())
+            .input();
+        assert!(Executor::new().run_with_input(Broken {}, &input).is_err());
+
+        let (output, logs) = crate::log::capture(|| {
+            Executor::new()
+                .report_init_failure(true)
+                .run_with_input(Broken {}, &input)
+                .unwrap()
+        });
+        assert!(output.contains(r#""result":"error""#));
+        assert!(output.contains(r#""result":"success""#));
+        assert!(logs.contains(&(
+            Level::Error,
+            "failed to initialize promise type: missing git binary".to_string()
+        )));
+    }
+
+    #[test]
+    fn it_initializes_eagerly() {
+        let input = "CFEngine 3.18.0 v1\n\n";
+        // Lazy init is not run without requests
+        assert!(Executor::new()
+            .run_with_input(Broken {}, input)
+            .unwrap_err()
+            .to_string()
+            .contains("end of input"));
+        let error = Executor::new()
+            .eager_init(true)
+            .run_with_input(Broken {}, input)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "failed to initialize promise type: missing git binary"
+        );
+        assert!(matches!(error, ExecutorError::Init(_)));
+    }
+
+    struct Foreign {}
+
+    impl PromiseType for Foreign {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn supported_platforms(&self) -> Vec<Platform> {
+            if cfg!(target_os = "aix") {
+                vec![Platform::Windows]
+            } else {
+                vec![Platform::Aix]
+            }
+        }
+
+        fn init(&mut self) -> ProtocolResult {
+            ProtocolResult::Failure("missing lslpp binary".to_string())
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn it_skips_unsupported_platforms() {
+        if !crate::log::compiled_in(Level::Notice) {
+            return;
+        }
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let (output, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            Executor::new().run_with_input(Foreign {}, &input).unwrap()
+        });
+        assert!(output.contains(r#""result":"kept""#));
+        assert!(logs.contains(&(
+            Level::Notice,
+            "test_promise_module is not supported on this platform, promises will be skipped"
+                .to_string()
+        )));
+    }
+
+    struct Conflicting {}
+
+    impl PromiseType for Conflicting {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("repo".to_string(), AttributeType::String)]
+        }
+
+        fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![
+                ("repo".to_string(), AttributeType::String),
+                ("branch".to_string(), AttributeType::String),
+            ]
+        }
+    }
+
+    #[test]
+    fn it_detects_conflicting_declarations() {
+        assert_eq!(
+            Executor::conflicting_declarations(&Conflicting {}),
+            vec!["Attribute repo is declared both required and optional".to_string()]
+        );
+        assert!(Executor::conflicting_declarations(&Defaulted {}).is_empty());
+        let (_, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            Executor::new().run_with_input(Conflicting {}, "CFEngine 3.18.0 v1\n\n")
+        });
+        assert_eq!(logs[0].0, Level::Critical);
+    }
+
+    struct Defaulted {}
+
+    impl PromiseType for Defaulted {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("mode".to_string(), AttributeType::FileMode)]
+        }
+
+        fn attribute_defaults(&self) -> Vec<(String, Value)> {
+            vec![("mode".to_string(), Value::from("0644"))]
+        }
+
+        fn check(&mut self, _promiser: &str, attributes: &Attributes) -> CheckResult {
+            match attributes.get_str("mode") {
+                Some("0644") => CheckResult::Kept,
+                _ => CheckResult::NotKept("wrong mode".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn it_applies_attribute_defaults() {
+        assert_eq!(
+            Executor::new().evaluate_once(&mut Defaulted {}, "/tmp/test", &Attributes::default()),
+            EvaluateOutcome::Kept
+        );
+        let input = TestSession::new()
+            .validate("/tmp/test", Attributes::default())
+            .input();
+        let output = Executor::new()
+            .run_with_input(Defaulted {}, &input)
+            .unwrap();
+        assert!(output.contains(r#""attributes":{"mode":"0644"}"#));
+    }
+
+    #[test]
+    fn it_logs_effective_attributes() {
+        if !crate::log::compiled_in(Level::Verbose) {
+            return;
+        }
+        let input = TestSession::new()
+            .log_level(LevelFilter::Verbose)
+            .validate("/tmp/test", Attributes::default())
+            .input();
+        let (_, logs) = crate::log::capture(|| {
+            Executor::new()
+                .log_effective_attributes(true)
+                .run_with_input(Defaulted {}, &input)
+                .unwrap()
+        });
+        assert!(logs.contains(&(
+            Level::Verbose,
+            r#"Effective attributes for /tmp/test: {"mode":"0644"}"#.to_string()
+        )));
+    }
+
+    struct Noisy {}
+
+    impl PromiseType for Noisy {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            crate::debug!(target: "noisy::http", "GET /index.html");
+            crate::debug!(target: "noisy::dns", "resolved example.com");
+            CheckResult::Kept
+        }
+    }
+
+    #[test]
+    fn it_filters_logs_by_target() {
+        if !crate::log::compiled_in(Level::Debug) {
+            return;
+        }
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let (_, logs) = crate::log::capture(|| {
+            Executor::new()
+                .log_target_level("noisy::http", LevelFilter::Debug)
+                .run_with_input(Noisy {}, &input)
+                .unwrap()
+        });
+        assert_eq!(logs, vec![(Level::Debug, "GET /index.html".to_string())]);
+
+        // Not kept for the next executor
+        let (_, logs) =
+            crate::log::capture(|| Executor::new().run_with_input(Noisy {}, &input).unwrap());
+        assert!(logs.is_empty());
+    }
+
+    struct Repeating {}
+
+    impl PromiseType for Repeating {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            for _ in 0..3 {
+                info!("retrying");
+            }
+            CheckResult::Kept
+        }
+    }
+
+    #[test]
+    fn it_resets_deduplication() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        crate::log::capture(|| {
+            Executor::new()
+                .deduplicate_logs(1, Duration::from_secs(60))
+                .run_with_input(Repeating {}, &input)
+                .unwrap()
+        });
+        let (_, logs) = crate::log::capture(|| {
+            set_max_level(LevelFilter::Info);
+            Executor::new()
+                .run_with_input(Repeating {}, &input)
+                .unwrap()
+        });
+        assert_eq!(logs, vec![(Level::Info, "retrying".to_string()); 3]);
+    }
+
+    struct Panicking {}
+
+    impl PromiseType for Panicking {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn check(&mut self, promiser: &str, _attributes: &Attributes) -> CheckResult {
+            panic!("unexpected promiser {}", promiser)
+        }
+    }
+
+    /// Panics in the given method
+    struct PanickingIn(&'static str);
+
+    impl PromiseType for PanickingIn {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn init(&mut self) -> ProtocolResult {
+            if self.0 == "init" {
+                panic!("in init");
+            }
+            ProtocolResult::Success
+        }
+
+        fn set_context(&mut self, _context: &PromiseContext) {
+            if self.0 == "set_context" {
+                panic!("in set_context");
+            }
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::Kept
+        }
+
+        fn terminate(&mut self) -> ProtocolResult {
+            if self.0 == "terminate" {
+                panic!("in terminate");
+            }
+            ProtocolResult::Success
+        }
+    }
+
+    #[test]
+    fn it_catches_panics_outside_evaluation() {
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        for method in ["init", "set_context", "terminate"] {
+            let (output, logs) = crate::log::capture(|| {
+                Executor::new()
+                    .report_init_failure(true)
+                    .run_with_input(PanickingIn(method), &input)
+                    .unwrap()
+            });
+            assert!(logs.contains(&(
+                Level::Critical,
+                format!("Promise type panicked: in {}", method)
+            )));
+            if method == "terminate" {
+                assert!(output.contains(r#""result":"kept""#));
+            } else {
+                assert!(output.contains(r#""result":"error""#));
+            }
+            assert_eq!(
+                output.contains(r#""operation":"terminate","result":"error""#),
+                method == "terminate"
+            );
+        }
+    }
+
+    #[test]
+    fn it_catches_panics() {
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let (output, logs) = crate::log::capture(|| {
+            Executor::new()
+                .run_with_input(Panicking {}, &input)
+                .unwrap()
+        });
+        assert!(output.contains(r#""result":"error""#));
+        assert!(output.contains(r#""operation":"terminate""#));
+        assert!(logs.contains(&(
+            Level::Critical,
+            "Promise type panicked: unexpected promiser /tmp/test".to_string()
+        )));
+    }
+
+    struct Counted {
+        validations: std::rc::Rc<std::cell::Cell<usize>>,
+    }
+
+    impl PromiseType for Counted {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("mode".to_string(), AttributeType::FileMode)]
+        }
+
+        fn validate(&self, _promiser: &str, _attributes: &Attributes) -> ValidateResult {
+            self.validations.set(self.validations.get() + 1);
+            ValidateResult::Valid
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::Kept
+        }
+    }
+
+    #[test]
+    fn it_reuses_validation_outcomes() {
+        let input = TestSession::new()
+            .validate("/tmp/test", attributes(json!({"mode": "0644"})))
+            .evaluate("/tmp/test", attributes(json!({"mode": "0644"})))
+            .evaluate("/tmp/test", attributes(json!({"mode": "0600"})))
+            .evaluate("/tmp/test", attributes(json!({"mode": "rwx"})))
+            .input();
+        let validations = std::rc::Rc::new(std::cell::Cell::new(0));
+        let output = Executor::new()
+            .run_with_input(
+                Counted {
+                    validations: validations.clone(),
+                },
+                &input,
+            )
+            .unwrap();
+        // Only the new valid attributes are validated again
+        assert_eq!(validations.get(), 2);
+        assert_eq!(output.matches(r#""result":"kept""#).count(), 2);
+        assert_eq!(output.matches(r#""result":"error""#).count(), 1);
+    }
+
+    struct Audit {}
+
+    impl PromiseType for Audit {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn default_action_policy(&self) -> ActionPolicy {
+            ActionPolicy::Warn
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("not there".to_string())
+        }
+    }
+
+    #[test]
+    fn it_never_applies_audit_only_promise_types() {
+        assert_eq!(
+            Executor::new().evaluate_once(&mut Audit {}, "/tmp/test", &Attributes::default()),
+            EvaluateOutcome::NotKept
+        );
+    }
+
+    #[test]
+    fn it_logs_audit_drift_at_configured_level() {
+        if !crate::log::compiled_in(Level::Warning) {
+            return;
+        }
+        let (outcome, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            Executor::new()
+                .audit_not_kept_level(Level::Warning)
+                .evaluate_once(&mut Audit {}, "/tmp/test", &Attributes::default())
+        });
+        assert_eq!(outcome, EvaluateOutcome::NotKept);
+        assert_eq!(logs, vec![(Level::Warning, "not there".to_string())]);
+    }
+
+    struct NoApply {}
+
+    impl PromiseType for NoApply {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("not there".to_string())
+        }
+    }
+
+    #[test]
+    fn it_reports_check_outcome_for_audit_only_results() {
+        let (outcome, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            Executor::new().evaluate_once(&mut NoApply {}, "/tmp/test", &Attributes::default())
+        });
+        assert_eq!(outcome, EvaluateOutcome::NotKept);
+        assert!(logs.iter().all(|(level, _)| *level != Level::Error));
+    }
+
+    #[test]
+    fn it_follows_action_policies() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let policy = |p: &str| {
+            let mut attributes = Attributes::default();
+            attributes.insert("action_policy".to_string(), Value::String(p.to_string()));
+            attributes
+        };
+        let evaluate = |executor: &Executor, attributes: &Attributes| {
+            executor.evaluate_once(&mut Test::new(ResourceKind::State), "/tmp/test", attributes)
+        };
+        let executor = Executor::new();
+        assert_eq!(
+            evaluate(&executor, &policy("fix")),
+            EvaluateOutcome::Repaired
+        );
+        assert_eq!(
+            evaluate(&executor, &policy("warn")),
+            EvaluateOutcome::NotKept
+        );
+        assert_eq!(
+            evaluate(&executor, &policy("unknown")),
+            EvaluateOutcome::NotKept
+        );
+        let (outcome, logs) = crate::log::capture(|| {
+            set_max_level(crate::LevelFilter::Info);
+            evaluate(&executor, &policy("nop"))
+        });
+        assert_eq!(outcome, EvaluateOutcome::NotKept);
+        assert_eq!(logs, vec![(Level::Info, "not there".to_string())]);
+
+        let executor = Executor::new().warn_only(true);
+        assert_eq!(
+            evaluate(&executor, &policy("fix")),
+            EvaluateOutcome::NotKept
+        );
+        assert_eq!(
+            evaluate(&executor, &Attributes::default()),
+            EvaluateOutcome::NotKept
+        );
+    }
+
+    #[test]
+    fn it_applies_batches() {
+        let executor = Executor::new();
+        let mut promise = Test::new(ResourceKind::State);
+        let mut check_only = Attributes::default();
+        check_only.insert(
+            "action_policy".to_string(),
+            Value::String("warn".to_string()),
+        );
+        let promises = vec![
+            ("/tmp/a".to_string(), Attributes::default()),
+            ("/tmp/b".to_string(), check_only),
+            ("/tmp/c".to_string(), Attributes::default()),
+        ];
+        assert_eq!(
+            executor.evaluate_batch_once(&mut promise, &promises),
+            vec![
+                EvaluateOutcome::Repaired,
+                EvaluateOutcome::NotKept,
+                EvaluateOutcome::Repaired
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_changes() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let mut promise = Test::new(ResourceKind::State);
+        promise.apply = Repair::new("updated")
+            .change(Change::modified("/etc/motd:1", "hello", "welcome"))
+            .into();
+        let (output, logs) = crate::log::capture(|| evaluate(promise));
+        assert!(!output.contains("changes"));
+        assert!(logs.contains(&(
+            Level::Info,
+            "/etc/motd:1: changed 'hello' to 'welcome'".to_string()
+        )));
+    }
+
+    #[test]
+    fn it_defines_result_classes() {
+        let executor = Executor::new().auto_classes(true);
+        assert_eq!(
+            executor.result_classes("/etc/motd", EvaluateOutcome::NotKept),
+            vec![Class::new("_etc_motd_not_kept")]
+        );
+        assert!(Executor::new()
+            .result_classes("/etc/motd", EvaluateOutcome::Kept)
+            .is_empty());
+    }
+
+    /// Records the size of each write call
+    #[derive(Default)]
+    struct Writes(Vec<usize>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_writes_responses_atomically() {
+        let mut writes = Writes::default();
+        Executor::new().write_line(&mut writes, "{}").unwrap();
+        assert_eq!(writes.0, vec![2, 2]);
+
+        let mut writes = Writes::default();
+        Executor::new()
+            .atomic_writes(true)
+            .write_line(&mut writes, "{}")
+            .unwrap();
+        assert_eq!(writes.0, vec![4]);
+    }
+
+    #[test]
+    fn it_counts_outcomes() {
+        let mut summary = Summary::default();
+        summary.add("/tmp/a", EvaluateOutcome::Kept);
+        summary.add("/tmp/b", EvaluateOutcome::Kept);
+        summary.add("/tmp/c", EvaluateOutcome::Repaired);
+        summary.add("/tmp/d", EvaluateOutcome::NotKept);
+        assert_eq!(summary.to_string(), "kept=2 repaired=1 not_kept=1 error=0");
+    }
+
+    #[test]
+    fn it_writes_summary_files() {
+        if !crate::log::compiled_in(Level::Error) {
+            return;
+        }
+        let input = TestSession::new()
+            .evaluate("/tmp/test", Attributes::default())
+            .input();
+        let path = std::env::temp_dir().join(format!("summary-{}.json", std::process::id()));
+        Executor::new()
+            .summary_file(&path)
+            .run_with_input(Test::new(ResourceKind::State), &input)
+            .unwrap();
+        let summary: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(summary["repaired"], 1);
+        assert_eq!(
+            summary["promises"],
+            serde_json::json!([{"promiser": "/tmp/test", "result": "repaired"}])
+        );
+
+        let (output, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            Executor::new()
+                .summary_file("/nonexistent/summary.json")
+                .run_with_input(Test::new(ResourceKind::State), &input)
+        });
+        assert!(output.unwrap().contains(r#""result":"success""#));
+        assert!(logs
+            .iter()
+            .any(|(l, m)| *l == Level::Error && m.starts_with("Could not write summary")));
+    }
+
+    #[test]
+    fn it_limits_line_size() {
+        let executor = Executor::new().max_line_bytes(4);
+        let mut input = "abcd\n\nabcde\n\n".as_bytes();
+        assert_eq!(executor.read_line(&mut input).unwrap(), "abcd");
+        assert!(executor.read_line(&mut input).is_err());
+        assert!(executor.read_line(&mut "".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn it_reads_multiline_requests() {
+        let executor = Executor::new();
+        let mut input = "{\n  \"a\": \"}{\\\"\",\n  \"b\": [1]\n}\n\nnext\n\n".as_bytes();
+        assert_eq!(
+            executor.read_line(&mut input).unwrap(),
+            "{\n  \"a\": \"}{\\\"\",\n  \"b\": [1]\n}"
+        );
+        assert_eq!(executor.read_line(&mut input).unwrap(), "next");
+
+        let executor = Executor::new().max_line_bytes(8);
+        let mut input = "{\n\"a\": 1,\n\"b\": 2\n}\n\n".as_bytes();
+        assert!(executor.read_line(&mut input).is_err());
+    }
+
+    #[test]
+    fn it_runs_single_steps() {
+        let executor = Executor::new();
+        let mut promise = Test::new(ResourceKind::State);
+        let mut attributes = Attributes::default();
+        assert_eq!(
+            executor.validate_once(&promise, "/tmp/test", &attributes),
+            ValidateOutcome::Valid
+        );
+        assert_eq!(
+            executor.evaluate_once(&mut promise, "/tmp/test", &attributes),
+            EvaluateOutcome::Repaired
+        );
+
+        attributes.insert("unknown".to_string(), Value::Bool(true));
+        assert_eq!(
+            executor.validate_once(&promise, "/tmp/test", &attributes),
+            ValidateOutcome::Invalid
+        );
+    }
+
+    #[test]
+    fn it_rolls_back_partial_changes() {
+        let mut promise = Test::new(ResourceKind::State);
+        promise.apply = ApplyResult::PartiallyApplied("interrupted".to_string());
+        assert!(evaluate(promise).contains(r#""result":"not_kept""#));
+
+        let mut promise = Test::new(ResourceKind::State);
+        promise.apply = ApplyResult::PartiallyApplied("interrupted".to_string());
+        promise.rollback = ProtocolResult::Failure("could not undo".to_string());
+        assert!(evaluate(promise).contains(r#""result":"error""#));
+    }
+}
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Shell-like glob patterns
+//!
+//! Supports `?`, `*`, `**`, bracket expressions (`[a-z]`, `[!0-9]` or `[^0-9]`) and `\` escapes.
+//! Only `**` matches across `/` separators.
+
+use anyhow::{bail, Error};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `**`
+    DoubleStar,
+    /// Bracket expression with inclusive ranges
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Literal(l) => *l == c,
+            Token::Any => c != '/',
+            Token::Class { negated, ranges } => {
+                c != '/' && ranges.iter().any(|(a, b)| (*a..=*b).contains(&c)) != *negated
+            }
+            Token::Star | Token::DoubleStar => unreachable!("wildcards match sequences"),
+        }
+    }
+}
+
+/// Compiled glob pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    /// Compile a pattern, rejecting unclosed brackets, reversed ranges and trailing escapes
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        let mut tokens = vec![];
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '?' => Token::Any,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    Token::DoubleStar
+                }
+                '*' => Token::Star,
+                '\\' => match chars.next() {
+                    Some(e) => Token::Literal(e),
+                    None => bail!("Invalid glob '{}': trailing escape", pattern),
+                },
+                '[' => {
+                    let negated = matches!(chars.peek(), Some('!') | Some('^'));
+                    if negated {
+                        chars.next();
+                    }
+                    let mut ranges = vec![];
+                    let mut first = true;
+                    loop {
+                        let start = match chars.next() {
+                            // A leading `]` is a literal
+                            Some(']') if !first => break,
+                            Some('\\') => chars.next(),
+                            c => c,
+                        };
+                        let start = match start {
+                            Some(s) => s,
+                            None => bail!("Invalid glob '{}': unclosed bracket", pattern),
+                        };
+                        first = false;
+                        let mut lookahead = chars.clone();
+                        let end = match (lookahead.next(), lookahead.next()) {
+                            (Some('-'), Some(end)) if end != ']' => {
+                                chars.next();
+                                chars.next();
+                                end
+                            }
+                            _ => start,
+                        };
+                        if start > end {
+                            bail!(
+                                "Invalid glob '{}': reversed range {}-{}",
+                                pattern,
+                                start,
+                                end
+                            );
+                        }
+                        ranges.push((start, end));
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Literal(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            tokens,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the whole string matches the pattern
+    pub fn is_match(&self, s: &str) -> bool {
+        let chars: Vec<char> = s.chars().collect();
+        // matches[j] is true when the remaining tokens match `chars[j..]`
+        let mut matches = vec![false; chars.len() + 1];
+        matches[chars.len()] = true;
+        for token in self.tokens.iter().rev() {
+            let next = matches;
+            matches = vec![false; chars.len() + 1];
+            for j in (0..=chars.len()).rev() {
+                matches[j] = match token {
+                    Token::Star => next[j] || j < chars.len() && chars[j] != '/' && matches[j + 1],
+                    Token::DoubleStar => next[j] || j < chars.len() && matches[j + 1],
+                    t => j < chars.len() && t.matches(chars[j]) && next[j + 1],
+                };
+            }
+        }
+        matches[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        Glob::new(pattern).unwrap().is_match(s)
+    }
+
+    #[test]
+    fn it_compiles_globs() {
+        assert!(Glob::new("*.conf").is_ok());
+        assert!(Glob::new("[]a]").is_ok());
+        assert!(Glob::new("[a-]").is_ok());
+        assert!(Glob::new("[a-z").is_err());
+        assert!(Glob::new("[z-a]").is_err());
+        assert!(Glob::new("[").is_err());
+        assert!(Glob::new("a\\").is_err());
+    }
+
+    #[test]
+    fn it_matches_globs() {
+        assert!(matches("*.conf", "httpd.conf"));
+        assert!(matches("*.conf", ".conf"));
+        assert!(!matches("*.conf", "conf.d/httpd.conf"));
+        assert!(matches("**.conf", "conf.d/httpd.conf"));
+        assert!(matches("/etc/**/*.conf", "/etc/httpd/conf.d/ssl.conf"));
+        assert!(matches("file?.[ch]", "file1.c"));
+        assert!(!matches("file?.[ch]", "file12.c"));
+        assert!(matches("[!0-9]x", "ax"));
+        assert!(!matches("[^0-9]x", "1x"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("", ""));
+        assert!(!matches("a*", "b"));
+    }
+}
@@ -1,10 +1,77 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2021 Normation SAS
 
-use std::{fmt, str::FromStr};
+use std::{
+    fmt, io,
+    ops::{BitAnd, BitOr},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use anyhow::{bail, Error};
 
+use crate::lock::PromiserLock;
+
+/// Set of optional protocol features, exchanged as header flags
+///
+/// Features are only used when supported by both the agent and the module.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(u8);
+
+/// Known capabilities with their header flag
+const CAPABILITY_FLAGS: [(Capabilities, &str); 2] = [
+    (Capabilities::ACTION_POLICY, "action_policy"),
+    (Capabilities::CHANGES, "changes"),
+];
+
+impl Capabilities {
+    /// Support for the `action_policy` attribute, i.e. warn-only mode
+    pub const ACTION_POLICY: Self = Self(1);
+    /// Support for change records in evaluate responses
+    pub const CHANGES: Self = Self(2);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse header flags, ignoring unknown ones
+    pub(crate) fn from_flags(flags: &[String]) -> Self {
+        CAPABILITY_FLAGS
+            .iter()
+            .filter(|(_, f)| flags.iter().any(|flag| flag == f))
+            .fold(Self::empty(), |c, (flag, _)| c | *flag)
+    }
+
+    /// Header flags for these capabilities
+    pub(crate) fn flags(self) -> Vec<String> {
+        CAPABILITY_FLAGS
+            .iter()
+            .filter(|(c, _)| self.contains(*c))
+            .map(|(_, f)| f.to_string())
+            .collect()
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) struct Header {
     /// The name of the sender
@@ -38,7 +105,10 @@ impl FromStr for Header {
         let header: Vec<&str> = s.splitn(4, ' ').collect();
 
         if header.len() < 3 {
-            bail!("Incomplete header");
+            bail!(
+                "Incomplete header '{}', expecting '<name> <version> <protocol version> [flags]'",
+                s
+            );
         }
 
         let name = header[0].to_string();
@@ -58,30 +128,91 @@ impl FromStr for Header {
     }
 }
 
+/// Agent information sent in the first line of the protocol
+#[derive(Debug, PartialEq, Clone)]
+pub struct InitSettings {
+    /// Name of the agent, "CFEngine"
+    pub agent_name: String,
+    /// Version of the agent, like "3.18.0"
+    pub agent_version: String,
+    /// Protocol version, "v1"
+    pub protocol_version: String,
+    /// Flags sent by the agent
+    pub flags: Vec<String>,
+    /// Directory where the module can persist data between runs
+    ///
+    /// It is not created by the executor.
+    pub state_dir: PathBuf,
+    /// Capabilities supported by both the agent and the module
+    pub capabilities: Capabilities,
+}
+
+impl InitSettings {
+    pub(crate) fn new(header: &Header, state_dir: PathBuf, capabilities: Capabilities) -> Self {
+        Self {
+            agent_name: header.name.clone(),
+            agent_version: header.version.clone(),
+            protocol_version: header.protocol_version.clone(),
+            flags: header.flags.clone(),
+            state_dir,
+            capabilities: header.capabilities() & capabilities,
+        }
+    }
+
+    /// Lock a promiser against other instances of the module, like concurrent agent runs
+    ///
+    /// Waits until the lock is available. Lock files are stored in a `locks` directory
+    /// in `state_dir`, created if needed. The lock is released when dropped, so a lock kept
+    /// in the promise type is released after `terminate`.
+    pub fn lock(&self, promiser: &str) -> io::Result<PromiserLock> {
+        PromiserLock::acquire(&self.state_dir.join("locks"), promiser, true)
+            .map(|l| l.expect("waiting lock is always acquired"))
+    }
+
+    /// Lock a promiser if no other instance of the module holds it
+    ///
+    /// Returns `None` without waiting otherwise.
+    pub fn try_lock(&self, promiser: &str) -> io::Result<Option<PromiserLock>> {
+        PromiserLock::acquire(&self.state_dir.join("locks"), promiser, false)
+    }
+}
+
 impl Header {
     pub(crate) fn compatibility(&self) -> Result<(), Error> {
         // Compatibility checks
+        let mut mismatches = vec![];
         if self.name != "CFEngine" {
-            bail!("Unknown agent {}, expecting 'CFEngine'", self.name);
+            mismatches.push(format!("agent is '{}', expecting 'CFEngine'", self.name));
         }
         if self.protocol_version != "v1" {
-            bail!(
-                "Incompatible protocol version {}, expecting v1",
+            mismatches.push(format!(
+                "protocol version is '{}', expecting 'v1'",
                 self.protocol_version
-            );
+            ));
         }
-        if !self.flags.is_empty() {
-            bail!("Expecting empty flags");
+        if !mismatches.is_empty() {
+            bail!(
+                "Incompatible agent header '{}': {}",
+                self,
+                mismatches.join(", ")
+            );
         }
         Ok(())
     }
 
-    pub(crate) fn new(name: String, version: String) -> Self {
+    /// Capabilities advertised in the flags, unknown flags are ignored
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        Capabilities::from_flags(&self.flags)
+    }
+
+    pub(crate) fn new(name: String, version: String, capabilities: Capabilities) -> Self {
+        let mut flags = vec!["json_based".to_string()];
+        flags.extend(capabilities.flags());
         Self {
             name,
             version,
             protocol_version: "v1".to_string(),
-            flags: vec!["json_based".to_string()],
+            flags,
         }
     }
 }
@@ -127,4 +258,62 @@ mod tests {
             .to_string()
         );
     }
+
+    #[test]
+    fn it_exposes_init_settings() {
+        let header = "CFEngine 3.18.0 v1".parse::<Header>().unwrap();
+        assert_eq!(
+            InitSettings::new(
+                &header,
+                PathBuf::from("/var/cfengine/state/test"),
+                Capabilities::ACTION_POLICY
+            ),
+            InitSettings {
+                agent_name: "CFEngine".to_string(),
+                agent_version: "3.18.0".to_string(),
+                protocol_version: "v1".to_string(),
+                flags: vec![],
+                state_dir: PathBuf::from("/var/cfengine/state/test"),
+                capabilities: Capabilities::empty(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_reports_incompatibilities() {
+        let header = "cf-agent 3.22.0 v2".parse::<Header>().unwrap();
+        assert_eq!(
+            header.compatibility().unwrap_err().to_string(),
+            "Incompatible agent header 'cf-agent 3.22.0 v2': agent is 'cf-agent', \
+             expecting 'CFEngine', protocol version is 'v2', expecting 'v1'"
+        );
+        assert!("CFEngine 3.16.0 v1"
+            .parse::<Header>()
+            .unwrap()
+            .compatibility()
+            .is_ok());
+        assert!("CFEngine 3.16.0".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn it_negotiates_capabilities() {
+        let header = "CFEngine 3.20.0 v1 action_policy future_flag"
+            .parse::<Header>()
+            .unwrap();
+        assert!(header.compatibility().is_ok());
+        assert_eq!(header.capabilities(), Capabilities::ACTION_POLICY);
+        assert_eq!(
+            (header.capabilities() & Capabilities::empty()),
+            Capabilities::empty()
+        );
+        assert_eq!(
+            Header::new(
+                "git_promise_module".to_string(),
+                "0.0.1".to_string(),
+                Capabilities::ACTION_POLICY
+            )
+            .to_string(),
+            "git_promise_module 0.0.1 v1 json_based action_policy"
+        );
+    }
 }
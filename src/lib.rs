@@ -15,21 +15,54 @@
 //! The library is generally build around a `PromiseType` trait describing a promise type's interface, and an `Executor`
 //! that handles the stdin/stdout communication and protocol serialization.
 
+#[doc(hidden)]
+pub use attribute::__attribute_value;
+#[cfg(feature = "derive")]
+pub use rudder_resource_derive::Promise;
+pub use semver::{Version, VersionReq};
 pub use serde_json::{Map, Value};
 
 pub use crate::{
-    attribute::AttributeType,
+    asynchronous::AsyncPromiseType,
+    attribute::{AttributeEnum, AttributeType, Attributes, EnumType, PromiseAttributes},
+    cron::CronSchedule,
+    error::ExecutorError,
     executor::Executor,
-    protocol::{ApplyResult, CheckResult, Class, ProtocolResult, ValidateResult},
+    glob::Glob,
+    header::{Capabilities, InitSettings},
+    lock::PromiserLock,
+    log::{max_level, Level, LevelFilter, Progress},
+    planned::PlannedPromiseType,
+    platform::Platform,
+    protocol::{
+        ActionPolicy, ApplyResult, Change, ChangeKind, CheckResult, Class, EvaluateOutcome,
+        PromiseContext, ProtocolOutcome, ProtocolResult, Repair, ValidateOutcome, ValidateResult,
+    },
+    registry::PromiseRegistry,
+    resource::ResourceKind,
+    timezone::TimeZone,
 };
 
+mod asynchronous;
 mod attribute;
+mod cron;
+mod error;
 mod executor;
+mod glob;
 mod header;
+mod iso;
+mod lock;
 #[macro_use]
 pub mod log;
+mod planned;
+mod platform;
 mod protocol;
+mod registry;
 mod resource;
+mod schema;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod timezone;
 
 #[macro_export]
 macro_rules! name {
@@ -49,12 +82,55 @@ macro_rules! version {
     };
 }
 
+/// Declares the attributes of a promise type from a `PromiseAttributes` type
+#[macro_export]
+macro_rules! attributes {
+    ($t:ty) => {
+        fn required_attributes(&self) -> Vec<(String, $crate::AttributeType)> {
+            <$t as $crate::PromiseAttributes>::required_attributes()
+        }
+
+        fn optional_attributes(&self) -> Vec<(String, $crate::AttributeType)> {
+            <$t as $crate::PromiseAttributes>::optional_attributes()
+        }
+    };
+}
+
 /// CFEngine promise type
 pub trait PromiseType {
     fn name(&self) -> &'static str;
     fn version(&self) -> &'static str;
     // no protocol versions as it is part of the executor
 
+    /// Kind of resource implemented by the promise type
+    ///
+    /// Defines how the executor uses `check` and `apply`.
+    fn kind(&self) -> ResourceKind {
+        ResourceKind::State
+    }
+
+    /// Action policy used when the policy does not specify one
+    ///
+    /// Audit-only promise types can use `ActionPolicy::Warn` to never be applied.
+    fn default_action_policy(&self) -> ActionPolicy {
+        ActionPolicy::Fix
+    }
+
+    /// Optional protocol features supported by the promise type
+    ///
+    /// They are advertised in the header.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    /// Platforms the promise type can run on
+    ///
+    /// An empty list means every platform. On other platforms, `init` is not called
+    /// and promises are reported as kept without being checked or applied.
+    fn supported_platforms(&self) -> Vec<Platform> {
+        vec![]
+    }
+
     /// List of required attributes with their type
     ///
     /// They will be checked before calling `validate`
@@ -75,6 +151,58 @@ pub trait PromiseType {
         vec![]
     }
 
+    /// Groups of attributes that cannot be used together
+    ///
+    /// They will be checked before calling `validate`
+    fn exclusive_groups(&self) -> Vec<Vec<String>> {
+        vec![]
+    }
+
+    /// Groups of attributes among which at least one is required
+    ///
+    /// They will be checked before calling `validate`
+    fn required_groups(&self) -> Vec<Vec<String>> {
+        vec![]
+    }
+
+    /// Attributes required only when another attribute has a given value
+    ///
+    /// Each item is `(trigger, value, required)`: when `trigger` equals `value`,
+    /// `required` has to be present. They will be checked before calling `validate`
+    fn conditional_requirements(&self) -> Vec<(String, Value, String)> {
+        vec![]
+    }
+
+    /// Attributes containing secrets, like passwords or tokens
+    ///
+    /// Their values are replaced by `***` in responses and debug output.
+    fn secret_attributes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// List of deprecated attribute names with their replacement
+    ///
+    /// Deprecated names are accepted with a warning, and renamed before
+    /// the attributes checks and the `validate` step.
+    fn deprecated_attributes(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// List of attribute aliases with their canonical name
+    ///
+    /// Aliases are accepted silently, and renamed like deprecated attributes.
+    fn attribute_aliases(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Default values of optional attributes
+    ///
+    /// Missing attributes get their default value once the attributes checks passed,
+    /// before the `validate`, `check` and `apply` steps. Echoed attributes include them.
+    fn attribute_defaults(&self) -> Vec<(String, Value)> {
+        vec![]
+    }
+
     /// Executed before any promise
     ///
     /// Can be used for set-up tasks
@@ -82,11 +210,39 @@ pub trait PromiseType {
         ProtocolResult::Success
     }
 
+    /// Executed before any promise, with information sent by the agent
+    ///
+    /// Calls `init` by default.
+    fn init_with_settings(&mut self, _settings: &InitSettings) -> ProtocolResult {
+        self.init()
+    }
+
+    /// Receive the location of the next promise in the policy
+    ///
+    /// Called before validating or evaluating each promise from the agent,
+    /// can be used to key state per bundle or improve messages.
+    fn set_context(&mut self, _context: &PromiseContext) {}
+
+    /// Targets of a promiser used as a pattern, like a glob matching files
+    ///
+    /// Each target is checked and applied as its own promise, and the response
+    /// reports the worst outcome. Defaults to the promiser itself.
+    fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+        vec![promiser.to_string()]
+    }
+
+    /// Check the promiser format, like being a path or a service name
+    ///
+    /// Called before the attributes checks and `validate`.
+    fn validate_promiser(&self, _promiser: &str) -> ValidateResult {
+        ValidateResult::Valid
+    }
+
     /// Checks parameter validity
     ///
     /// Should be used for parameters validation, additionally to
     /// `required_attributes` and `optional_attributes`.
-    fn validate(&self, _promiser: &str, _attributes: &Map<String, Value>) -> ValidateResult {
+    fn validate(&self, _promiser: &str, _attributes: &Attributes) -> ValidateResult {
         ValidateResult::Valid
     }
 
@@ -96,17 +252,60 @@ pub trait PromiseType {
     ///
     /// Does not need to be implemented for promises that should be evaluated every time
     /// (usually actions).
-    fn check(&mut self, _promiser: &str, _attributes: &Map<String, Value>) -> CheckResult {
+    fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
         CheckResult::AlwaysApply
     }
 
+    /// Describe the changes `apply` would make
+    ///
+    /// Only called in warn-only mode when the promise is not kept,
+    /// and each item is logged at notice level.
+    fn plan(&self, _promiser: &str, _attributes: &Attributes) -> Vec<String> {
+        vec![]
+    }
+
     /// Apply the policy and make changes
     ///
     /// Assumes validation has already been done
-    fn apply(&mut self, _promiser: &str, _attributes: &Map<String, Value>) -> ApplyResult {
+    fn apply(&mut self, _promiser: &str, _attributes: &Attributes) -> ApplyResult {
         ApplyResult::AuditOnly
     }
 
+    /// Apply the policy, reporting progress of long-running changes
+    ///
+    /// Calls `apply` by default.
+    fn apply_with_progress(
+        &mut self,
+        promiser: &str,
+        attributes: &Attributes,
+        _progress: &mut Progress<'_>,
+    ) -> ApplyResult {
+        self.apply(promiser, attributes)
+    }
+
+    /// Apply the policy for several promises at once
+    ///
+    /// Returns one result per promise, in the same order. Defaults to calling
+    /// `apply_with_progress` for each promise.
+    fn apply_batch(
+        &mut self,
+        promises: &[(String, Attributes)],
+        progress: &mut Progress<'_>,
+    ) -> Vec<ApplyResult> {
+        promises
+            .iter()
+            .map(|(promiser, attributes)| self.apply_with_progress(promiser, attributes, progress))
+            .collect()
+    }
+
+    /// Undo partial changes
+    ///
+    /// Called when `apply` returned `PartiallyApplied`, an error
+    /// here makes the evaluation result an error.
+    fn rollback(&mut self, _promiser: &str, _attributes: &Attributes) -> ProtocolResult {
+        ProtocolResult::Success
+    }
+
     /// Run before normal executor termination,
     /// can be used for clean up tasks.
     fn terminate(&mut self) -> ProtocolResult {
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Advisory locks on promisers, shared by all instances of a module
+
+use std::{
+    fmt::Write,
+    fs::{self, File, TryLockError},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Longest encoded promiser kept in lock file names, under the usual 255 bytes limit
+const MAX_NAME_BYTES: usize = 200;
+
+/// 64-bit FNV-1a hash, stable across builds unlike `DefaultHasher`
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Exclusive OS file lock on a promiser
+///
+/// Released when dropped.
+#[derive(Debug)]
+pub struct PromiserLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl PromiserLock {
+    /// Lock file of a promiser in `dir`, with unsafe characters percent-encoded
+    ///
+    /// Long names are truncated and suffixed with a hash of the promiser.
+    fn lock_path(dir: &Path, promiser: &str) -> PathBuf {
+        let mut name = String::new();
+        for b in promiser.bytes() {
+            if b.is_ascii_alphanumeric() || b"._-".contains(&b) {
+                name.push(b as char);
+            } else {
+                let _ = write!(name, "%{:02X}", b);
+            }
+        }
+        if name.len() > MAX_NAME_BYTES {
+            // Keep room for the hash, without cutting an encoded byte
+            name.truncate(MAX_NAME_BYTES - 17);
+            if let Some(escape) = name[name.len().saturating_sub(2)..].find('%') {
+                name.truncate(name.len().saturating_sub(2) + escape);
+            }
+            let _ = write!(name, "-{:016x}", fnv1a(promiser.as_bytes()));
+        }
+        dir.join(format!("{}.lock", name))
+    }
+
+    /// Lock a promiser, waiting for other holders if `wait` is set
+    ///
+    /// Returns `None` when the lock is held elsewhere and `wait` is not set.
+    pub(crate) fn acquire(dir: &Path, promiser: &str, wait: bool) -> io::Result<Option<Self>> {
+        fs::create_dir_all(dir)?;
+        let path = Self::lock_path(dir, promiser);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if wait {
+            file.lock()?;
+        } else {
+            match file.try_lock() {
+                Ok(()) => (),
+                Err(TryLockError::WouldBlock) => return Ok(None),
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+        Ok(Some(Self { _file: file, path }))
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn it_locks_promisers() {
+        let dir = env::temp_dir().join(format!("rudder_resource_lock_{}", std::process::id()));
+        let lock = PromiserLock::acquire(&dir, "/etc/motd", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(lock.path(), dir.join("%2Fetc%2Fmotd.lock"));
+        assert!(PromiserLock::acquire(&dir, "/etc/motd", false)
+            .unwrap()
+            .is_none());
+        assert!(PromiserLock::acquire(&dir, "/etc/issue", false)
+            .unwrap()
+            .is_some());
+
+        drop(lock);
+        assert!(PromiserLock::acquire(&dir, "/etc/motd", true)
+            .unwrap()
+            .is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_locks_long_promisers() {
+        let dir = env::temp_dir().join(format!("rudder_resource_long_{}", std::process::id()));
+        let long = "/very/deep/directory".repeat(20);
+        let lock = PromiserLock::acquire(&dir, &long, false).unwrap().unwrap();
+        let name = lock.path().file_name().unwrap().to_str().unwrap();
+        assert!(name.len() <= MAX_NAME_BYTES + ".lock".len());
+        assert!(name.starts_with("%2Fvery%2Fdeep"));
+
+        // Promisers sharing a long prefix get distinct locks
+        let other = format!("{}/other", long);
+        assert!(PromiserLock::acquire(&dir, &other, false)
+            .unwrap()
+            .is_some());
+        assert!(PromiserLock::acquire(&dir, &long, false).unwrap().is_none());
+        drop(lock);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
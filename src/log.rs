@@ -1,6 +1,11 @@
 use std::{
-    cmp, fmt, mem,
-    sync::atomic::{AtomicUsize, Ordering},
+    cmp, fmt,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use serde::{Deserialize, Serialize};
@@ -35,8 +40,10 @@ impl fmt::Display for Level {
     }
 }
 
+/// Maximum level of logs to output
+///
+/// Ordered by verbosity, `Debug` being the greatest.
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Eq)]
-#[repr(usize)]
 #[serde(rename_all = "lowercase")]
 pub enum LevelFilter {
     Critical,
@@ -48,24 +55,72 @@ pub enum LevelFilter {
     Debug,
 }
 
+impl Level {
+    /// Verbosity of the level, greater is more verbose
+    ///
+    /// Defines the ordering of levels and filters, independently of the declaration order.
+    #[inline]
+    pub fn severity(self) -> usize {
+        match self {
+            Level::Critical => 0,
+            Level::Error => 1,
+            Level::Warning => 2,
+            Level::Notice => 3,
+            Level::Info => 4,
+            Level::Verbose => 5,
+            Level::Debug => 6,
+        }
+    }
+}
+
+impl LevelFilter {
+    /// Verbosity of the most verbose level allowed, see `Level::severity`
+    #[inline]
+    pub const fn severity(self) -> usize {
+        match self {
+            LevelFilter::Critical => 0,
+            LevelFilter::Error => 1,
+            LevelFilter::Warning => 2,
+            LevelFilter::Notice => 3,
+            LevelFilter::Info => 4,
+            LevelFilter::Verbose => 5,
+            LevelFilter::Debug => 6,
+        }
+    }
+
+    /// Filter of the given severity, `Debug` for unknown ones
+    #[inline]
+    fn from_severity(severity: usize) -> Self {
+        match severity {
+            0 => LevelFilter::Critical,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warning,
+            3 => LevelFilter::Notice,
+            4 => LevelFilter::Info,
+            5 => LevelFilter::Verbose,
+            _ => LevelFilter::Debug,
+        }
+    }
+}
+
 impl Ord for Level {
     #[inline]
     fn cmp(&self, other: &Level) -> cmp::Ordering {
-        (*self as usize).cmp(&(*other as usize))
+        self.severity().cmp(&other.severity())
     }
 }
 
 impl PartialEq for Level {
     #[inline]
     fn eq(&self, other: &Level) -> bool {
-        *self as usize == *other as usize
+        self.severity() == other.severity()
     }
 }
 
 impl PartialEq<LevelFilter> for Level {
     #[inline]
     fn eq(&self, other: &LevelFilter) -> bool {
-        *self as usize == *other as usize
+        self.severity() == other.severity()
     }
 }
 
@@ -77,49 +132,63 @@ impl PartialOrd for Level {
 
     #[inline]
     fn lt(&self, other: &Level) -> bool {
-        (*self as usize) < *other as usize
+        self.severity() < other.severity()
     }
 
     #[inline]
     fn le(&self, other: &Level) -> bool {
-        *self as usize <= *other as usize
+        self.severity() <= other.severity()
     }
 
     #[inline]
     fn gt(&self, other: &Level) -> bool {
-        *self as usize > *other as usize
+        self.severity() > other.severity()
     }
 
     #[inline]
     fn ge(&self, other: &Level) -> bool {
-        *self as usize >= *other as usize
+        self.severity() >= other.severity()
     }
 }
 
 impl PartialOrd<LevelFilter> for Level {
     #[inline]
     fn partial_cmp(&self, other: &LevelFilter) -> Option<cmp::Ordering> {
-        Some((*self as usize).cmp(&(*other as usize)))
+        Some(self.severity().cmp(&other.severity()))
     }
 
     #[inline]
     fn lt(&self, other: &LevelFilter) -> bool {
-        (*self as usize) < *other as usize
+        self.severity() < other.severity()
     }
 
     #[inline]
     fn le(&self, other: &LevelFilter) -> bool {
-        *self as usize <= *other as usize
+        self.severity() <= other.severity()
     }
 
     #[inline]
     fn gt(&self, other: &LevelFilter) -> bool {
-        *self as usize > *other as usize
+        self.severity() > other.severity()
     }
 
     #[inline]
     fn ge(&self, other: &LevelFilter) -> bool {
-        *self as usize >= *other as usize
+        self.severity() >= other.severity()
+    }
+}
+
+impl Ord for LevelFilter {
+    #[inline]
+    fn cmp(&self, other: &LevelFilter) -> cmp::Ordering {
+        self.severity().cmp(&other.severity())
+    }
+}
+
+impl PartialOrd for LevelFilter {
+    #[inline]
+    fn partial_cmp(&self, other: &LevelFilter) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -135,22 +204,280 @@ impl PartialOrd<LevelFilter> for Level {
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+/// Most verbose level compiled in, set with the `max_level_*` and `release_max_level_*` features
+///
+/// Logs above it are removed at compile time, whatever the level requested by the agent.
+/// The most restrictive feature wins, and `release_max_level_*` features only apply to
+/// builds without debug assertions.
+pub const STATIC_MAX_LEVEL: LevelFilter = static_max_level(
+    cfg!(debug_assertions),
+    max_level_feature(),
+    release_max_level_feature(),
+);
+
+/// Most restrictive of the enabled `max_level_*` features
+const fn max_level_feature() -> LevelFilter {
+    if cfg!(feature = "max_level_critical") {
+        LevelFilter::Critical
+    } else if cfg!(feature = "max_level_error") {
+        LevelFilter::Error
+    } else if cfg!(feature = "max_level_warning") {
+        LevelFilter::Warning
+    } else if cfg!(feature = "max_level_notice") {
+        LevelFilter::Notice
+    } else if cfg!(feature = "max_level_info") {
+        LevelFilter::Info
+    } else if cfg!(feature = "max_level_verbose") {
+        LevelFilter::Verbose
+    } else {
+        LevelFilter::Debug
+    }
+}
+
+/// Most restrictive of the enabled `release_max_level_*` features, if any
+const fn release_max_level_feature() -> Option<LevelFilter> {
+    if cfg!(feature = "release_max_level_critical") {
+        Some(LevelFilter::Critical)
+    } else if cfg!(feature = "release_max_level_error") {
+        Some(LevelFilter::Error)
+    } else if cfg!(feature = "release_max_level_warning") {
+        Some(LevelFilter::Warning)
+    } else if cfg!(feature = "release_max_level_notice") {
+        Some(LevelFilter::Notice)
+    } else if cfg!(feature = "release_max_level_info") {
+        Some(LevelFilter::Info)
+    } else if cfg!(feature = "release_max_level_verbose") {
+        Some(LevelFilter::Verbose)
+    } else {
+        None
+    }
+}
+
+const fn static_max_level(
+    debug_assertions: bool,
+    max: LevelFilter,
+    release_max: Option<LevelFilter>,
+) -> LevelFilter {
+    match release_max {
+        Some(release_max) if !debug_assertions && release_max.severity() < max.severity() => {
+            release_max
+        }
+        _ => max,
+    }
+}
+
 static MAX_LOG_LEVEL_FILTER: AtomicUsize = AtomicUsize::new(0);
 
 #[inline]
 pub(crate) fn set_max_level(level: LevelFilter) {
-    MAX_LOG_LEVEL_FILTER.store(level as usize, Ordering::SeqCst)
+    MAX_LOG_LEVEL_FILTER.store(level.severity(), Ordering::SeqCst)
 }
 
+/// Log level requested by the agent for the current request
+///
+/// Can be used to skip expensive diagnostics:
+///
+/// ```
+/// use rudder_resource::log::{max_level, LevelFilter};
+///
+/// if max_level() >= LevelFilter::Verbose {
+///     // compute and log details
+/// }
+/// ```
 #[inline(always)]
 pub fn max_level() -> LevelFilter {
-    // Since `LevelFilter` is `repr(usize)`,
-    // this transmute is sound if and only if `MAX_LOG_LEVEL_FILTER`
-    // is set to a usize that is a valid discriminant for `LevelFilter`.
-    // Since `MAX_LOG_LEVEL_FILTER` is private, the only time it's set
-    // is by `set_max_level` above, i.e. by casting a `LevelFilter` to `usize`.
-    // So any usize stored in `MAX_LOG_LEVEL_FILTER` is a valid discriminant.
-    unsafe { mem::transmute(MAX_LOG_LEVEL_FILTER.load(Ordering::Relaxed)) }
+    LevelFilter::from_severity(MAX_LOG_LEVEL_FILTER.load(Ordering::Relaxed))
+}
+
+static HAS_TARGET_LEVELS: AtomicBool = AtomicBool::new(false);
+static TARGET_LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+
+/// Set log levels overriding the global one for some targets
+pub(crate) fn set_target_levels(levels: Vec<(String, LevelFilter)>) {
+    HAS_TARGET_LEVELS.store(!levels.is_empty(), Ordering::SeqCst);
+    *TARGET_LEVELS.write().unwrap_or_else(|e| e.into_inner()) = levels;
+}
+
+/// Whether a target is the given one or one of its submodules
+fn target_matches(target: &str, filter: &str) -> bool {
+    target
+        .strip_prefix(filter)
+        .map(|rest| rest.is_empty() || rest.starts_with("::"))
+        .unwrap_or(false)
+}
+
+/// Whether a log should be written, using the most specific target level if any
+#[doc(hidden)]
+#[inline]
+pub fn __enabled(level: Level, target: &str) -> bool {
+    if !HAS_TARGET_LEVELS.load(Ordering::Relaxed) {
+        return level <= max_level();
+    }
+    let levels = TARGET_LEVELS.read().unwrap_or_else(|e| e.into_inner());
+    let filter = levels
+        .iter()
+        .filter(|(t, _)| target_matches(target, t))
+        .max_by_key(|(t, _)| t.len())
+        .map(|(_, f)| *f)
+        .unwrap_or_else(max_level);
+    level <= filter
+}
+
+#[cfg(any(test, feature = "testing"))]
+thread_local! {
+    /// Logs captured instead of being written to stdout
+    static CAPTURED: std::cell::RefCell<Option<Vec<(Level, String)>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Run a function, returning the logs it produced instead of writing them
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn capture<R, F: FnOnce() -> R>(f: F) -> (R, Vec<(Level, String)>) {
+    CAPTURED.with(|c| *c.borrow_mut() = Some(vec![]));
+    let result = f();
+    let logs = CAPTURED.with(|c| c.borrow_mut().take()).unwrap_or_default();
+    (result, logs)
+}
+
+/// Whether logs at this level are compiled in
+///
+/// Tests asserting logs are skipped when a `max_level_*` feature strips them.
+#[cfg(test)]
+pub(crate) fn compiled_in(level: Level) -> bool {
+    level <= STATIC_MAX_LEVEL
+}
+
+/// Record the log if logs are being captured
+#[allow(unused_variables)]
+fn captured(level: Level, args: &fmt::Arguments<'_>) -> bool {
+    #[cfg(any(test, feature = "testing"))]
+    {
+        CAPTURED.with(|c| match c.borrow_mut().as_mut() {
+            Some(logs) => {
+                logs.push((level, args.to_string()));
+                true
+            }
+            None => false,
+        })
+    }
+    #[cfg(not(any(test, feature = "testing")))]
+    false
+}
+
+/// Collapses identical consecutive logs
+#[derive(Debug)]
+struct Deduplication {
+    /// Identical consecutive logs written before collapsing the next ones
+    threshold: usize,
+    /// Maximum duration of a series of identical logs
+    window: Duration,
+    /// Last log, with its number of occurrences and first occurrence
+    last: Option<(Level, String, usize, Instant)>,
+}
+
+impl Deduplication {
+    fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            last: None,
+        }
+    }
+
+    /// Logs to write for a new log
+    fn push(&mut self, level: Level, message: String, now: Instant) -> Vec<(Level, String)> {
+        if let Some((l, m, count, since)) = self.last.as_mut() {
+            if *l == level && *m == message && now.duration_since(*since) < self.window {
+                *count += 1;
+                return if *count <= self.threshold {
+                    vec![(level, message)]
+                } else {
+                    vec![]
+                };
+            }
+        }
+        let mut logs: Vec<_> = self.flush().into_iter().collect();
+        logs.push((level, message.clone()));
+        self.last = Some((level, message, 1, now));
+        logs
+    }
+
+    /// Summary of the collapsed logs of the current series, if any
+    fn flush(&mut self) -> Option<(Level, String)> {
+        match self.last.take() {
+            Some((level, message, count, _)) if count > self.threshold => Some((
+                level,
+                format!("{} (repeated {} times)", message, count - self.threshold),
+            )),
+            _ => None,
+        }
+    }
+}
+
+static DEDUPLICATION: Mutex<Option<Deduplication>> = Mutex::new(None);
+
+/// Collapse identical consecutive logs after `threshold` occurrences,
+/// for series shorter than `window`, or stop collapsing them with `None`
+pub(crate) fn set_deduplication(settings: Option<(usize, Duration)>) {
+    *DEDUPLICATION.lock().unwrap_or_else(|e| e.into_inner()) =
+        settings.map(|(threshold, window)| Deduplication::new(threshold, window));
+}
+
+/// Write the summary of the logs collapsed so far
+pub(crate) fn flush_repeated() {
+    let summary = DEDUPLICATION
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .and_then(|d| d.flush());
+    if let Some((level, message)) = summary {
+        write(level, format_args!("{}", message));
+    }
+}
+
+fn write(level: Level, args: fmt::Arguments<'_>) {
+    if captured(level, &args) {
+        return;
+    }
+    println!("log_{}={}", level, args);
+}
+
+#[doc(hidden)]
+pub fn __write(level: Level, args: fmt::Arguments<'_>) {
+    let logs = match DEDUPLICATION
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+    {
+        Some(d) => d.push(level, args.to_string(), Instant::now()),
+        None => return write(level, args),
+    };
+    for (level, message) in logs {
+        write(level, format_args!("{}", message));
+    }
+}
+
+/// Sink for progress messages of long-running applies, like a big download
+///
+/// Messages are written as verbose logs, with the promise type name as target,
+/// and flushed immediately.
+pub struct Progress<'a> {
+    target: &'a str,
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(target: &'a str) -> Self {
+        Self { target }
+    }
+
+    /// Report progress, if the agent requested verbose logs
+    pub fn report<M: fmt::Display>(&mut self, message: M) {
+        if Level::Verbose > STATIC_MAX_LEVEL || !__enabled(Level::Verbose, self.target) {
+            return;
+        }
+        __write(Level::Verbose, format_args!("{}", message));
+        // Progress is best effort
+        let _ = io::stdout().flush();
+    }
 }
 
 #[doc(hidden)]
@@ -158,11 +485,8 @@ pub fn max_level() -> LevelFilter {
 macro_rules! log {
     (target: $target:expr, $lvl:expr, $($arg:tt)+) => ({
         let lvl = $lvl;
-        if lvl <= $crate::log::max_level() {
-            std::println!(
-                "log_{}={}",
-                lvl, __log_format_args!($($arg)+)
-            );
+        if lvl <= $crate::log::STATIC_MAX_LEVEL && $crate::log::__enabled(lvl, $target) {
+            $crate::log::__write(lvl, __log_format_args!($($arg)+));
         }
     });
     ($lvl:expr, $($arg:tt)+) => (log!(target: __log_module_path!(), $lvl, $($arg)+))
@@ -254,6 +578,14 @@ macro_rules! debug {
     )
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_module_path {
+    () => {
+        module_path!()
+    };
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __log_format_args {
@@ -261,3 +593,93 @@ macro_rules! __log_format_args {
         format_args!($($args)*)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_collapses_repeated_logs() {
+        let mut dedup = Deduplication::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        let mut push = |message: &str, secs: u64| {
+            dedup.push(
+                Level::Warning,
+                message.to_string(),
+                start + Duration::from_secs(secs),
+            )
+        };
+        let warning = |message: &str| (Level::Warning, message.to_string());
+
+        assert_eq!(push("retrying", 0), vec![warning("retrying")]);
+        assert_eq!(push("retrying", 1), vec![warning("retrying")]);
+        assert_eq!(push("retrying", 2), vec![]);
+        assert_eq!(push("retrying", 3), vec![]);
+        assert_eq!(
+            push("giving up", 4),
+            vec![warning("retrying (repeated 2 times)"), warning("giving up")]
+        );
+        // Series are limited by the window
+        assert_eq!(push("giving up", 5), vec![warning("giving up")]);
+        assert_eq!(push("giving up", 6), vec![]);
+        assert_eq!(
+            push("giving up", 20),
+            vec![
+                warning("giving up (repeated 1 times)"),
+                warning("giving up")
+            ]
+        );
+        assert_eq!(dedup.flush(), None);
+    }
+
+    #[test]
+    fn it_matches_targets() {
+        assert!(target_matches("my_module::http", "my_module::http"));
+        assert!(target_matches("my_module::http::client", "my_module::http"));
+        assert!(!target_matches("my_module::https", "my_module::http"));
+        assert!(!target_matches("my_module", "my_module::http"));
+    }
+
+    #[test]
+    fn it_keeps_the_most_restrictive_static_level() {
+        use LevelFilter::*;
+        assert_eq!(static_max_level(true, Debug, None), Debug);
+        assert_eq!(static_max_level(false, Info, None), Info);
+        // Release features only apply without debug assertions
+        assert_eq!(static_max_level(true, Info, Some(Error)), Info);
+        assert_eq!(static_max_level(false, Info, Some(Error)), Error);
+        assert_eq!(static_max_level(false, Error, Some(Info)), Error);
+    }
+
+    #[test]
+    fn it_orders_levels_by_verbosity() {
+        let levels = [
+            Level::Critical,
+            Level::Error,
+            Level::Warning,
+            Level::Notice,
+            Level::Info,
+            Level::Verbose,
+            Level::Debug,
+        ];
+        let filters = [
+            LevelFilter::Critical,
+            LevelFilter::Error,
+            LevelFilter::Warning,
+            LevelFilter::Notice,
+            LevelFilter::Info,
+            LevelFilter::Verbose,
+            LevelFilter::Debug,
+        ];
+        for (i, level) in levels.iter().enumerate() {
+            assert_eq!(level.severity(), i);
+            assert_eq!(filters[i].severity(), i);
+            assert_eq!(LevelFilter::from_severity(i), filters[i]);
+            for (j, filter) in filters.iter().enumerate() {
+                assert_eq!(*level <= *filter, i <= j);
+                assert_eq!(levels[j] > *level, j > i);
+                assert_eq!(filters[i] < *filter, i < j);
+            }
+        }
+    }
+}
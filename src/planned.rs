@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Promise types passing a change plan from check to apply
+//!
+//! Associated type defaults are not available in stable Rust, so this
+//! is a separate trait instead of a `Plan` type on `PromiseType`.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{
+    ActionPolicy, ApplyResult, AttributeType, Attributes, Capabilities, CheckResult, InitSettings,
+    Platform, PromiseContext, PromiseType, ProtocolResult, ResourceKind, ValidateResult,
+};
+
+/// CFEngine promise type whose check computes the changes to apply
+///
+/// All other methods come from `PromiseType`, whose `check` and `apply`
+/// methods are not used.
+pub trait PlannedPromiseType: PromiseType {
+    /// Changes computed by `check_plan`, for `apply_plan`
+    type Plan;
+
+    /// Test if the policy is applied, and compute the changes to make otherwise
+    ///
+    /// Assumes validation has already been done.
+    fn check_plan(&mut self, promiser: &str, attributes: &Attributes) -> (CheckResult, Self::Plan);
+
+    /// Apply the plan computed by the last `check_plan` call for this promiser
+    ///
+    /// The plan is `None` when check was not called, for `ResourceKind::Action` resources.
+    fn apply_plan(
+        &mut self,
+        promiser: &str,
+        attributes: &Attributes,
+        plan: Option<Self::Plan>,
+    ) -> ApplyResult;
+}
+
+/// Exposes a planned promise type as a plain one, keeping the plans between check and apply
+pub(crate) struct Planned<T: PlannedPromiseType> {
+    promise: T,
+    /// Plans by promiser, as several checks can run before applying a batch
+    plans: HashMap<String, T::Plan>,
+}
+
+impl<T: PlannedPromiseType> Planned<T> {
+    pub(crate) fn new(promise: T) -> Self {
+        Self {
+            promise,
+            plans: HashMap::new(),
+        }
+    }
+}
+
+impl<T: PlannedPromiseType> PromiseType for Planned<T> {
+    fn name(&self) -> &'static str {
+        self.promise.name()
+    }
+
+    fn version(&self) -> &'static str {
+        self.promise.version()
+    }
+
+    fn kind(&self) -> ResourceKind {
+        self.promise.kind()
+    }
+
+    fn default_action_policy(&self) -> ActionPolicy {
+        self.promise.default_action_policy()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.promise.capabilities()
+    }
+
+    fn supported_platforms(&self) -> Vec<Platform> {
+        self.promise.supported_platforms()
+    }
+
+    fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.promise.required_attributes()
+    }
+
+    fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.promise.optional_attributes()
+    }
+
+    fn exclusive_groups(&self) -> Vec<Vec<String>> {
+        self.promise.exclusive_groups()
+    }
+
+    fn required_groups(&self) -> Vec<Vec<String>> {
+        self.promise.required_groups()
+    }
+
+    fn conditional_requirements(&self) -> Vec<(String, Value, String)> {
+        self.promise.conditional_requirements()
+    }
+
+    fn secret_attributes(&self) -> Vec<String> {
+        self.promise.secret_attributes()
+    }
+
+    fn deprecated_attributes(&self) -> Vec<(String, String)> {
+        self.promise.deprecated_attributes()
+    }
+
+    fn attribute_aliases(&self) -> Vec<(String, String)> {
+        self.promise.attribute_aliases()
+    }
+
+    fn attribute_defaults(&self) -> Vec<(String, Value)> {
+        self.promise.attribute_defaults()
+    }
+
+    fn init(&mut self) -> ProtocolResult {
+        self.promise.init()
+    }
+
+    fn init_with_settings(&mut self, settings: &InitSettings) -> ProtocolResult {
+        self.promise.init_with_settings(settings)
+    }
+
+    fn set_context(&mut self, context: &PromiseContext) {
+        // A new request, plans of audited promises were not applied
+        self.plans.clear();
+        self.promise.set_context(context)
+    }
+
+    fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+        self.promise.expand_promiser(promiser)
+    }
+
+    fn validate_promiser(&self, promiser: &str) -> ValidateResult {
+        self.promise.validate_promiser(promiser)
+    }
+
+    fn validate(&self, promiser: &str, attributes: &Attributes) -> ValidateResult {
+        self.promise.validate(promiser, attributes)
+    }
+
+    fn check(&mut self, promiser: &str, attributes: &Attributes) -> CheckResult {
+        let (result, plan) = self.promise.check_plan(promiser, attributes);
+        // Only keep plans that can be applied
+        let to_apply = matches!(result, CheckResult::NotKept(_) | CheckResult::AlwaysApply);
+        if to_apply && self.promise.kind() != ResourceKind::Check {
+            self.plans.insert(promiser.to_string(), plan);
+        }
+        result
+    }
+
+    fn plan(&self, promiser: &str, attributes: &Attributes) -> Vec<String> {
+        self.promise.plan(promiser, attributes)
+    }
+
+    fn apply(&mut self, promiser: &str, attributes: &Attributes) -> ApplyResult {
+        let plan = self.plans.remove(promiser);
+        self.promise.apply_plan(promiser, attributes, plan)
+    }
+
+    fn rollback(&mut self, promiser: &str, attributes: &Attributes) -> ProtocolResult {
+        self.promise.rollback(promiser, attributes)
+    }
+
+    fn terminate(&mut self) -> ProtocolResult {
+        self.promise.terminate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{name, version, Executor};
+
+    struct Lines {}
+
+    impl PromiseType for Lines {
+        name!("test_promise_module");
+        version!("0.0.1");
+    }
+
+    impl PlannedPromiseType for Lines {
+        type Plan = Vec<String>;
+
+        fn check_plan(
+            &mut self,
+            promiser: &str,
+            _attributes: &Attributes,
+        ) -> (CheckResult, Vec<String>) {
+            let missing = vec![format!("{} line", promiser)];
+            (CheckResult::NotKept("missing lines".to_string()), missing)
+        }
+
+        fn apply_plan(
+            &mut self,
+            _promiser: &str,
+            _attributes: &Attributes,
+            plan: Option<Vec<String>>,
+        ) -> ApplyResult {
+            match plan {
+                Some(lines) => ApplyResult::Repaired(format!("added {}", lines.join(", "))),
+                None => ApplyResult::Error("no plan".to_string()),
+            }
+        }
+    }
+
+    struct Kept {}
+
+    impl PromiseType for Kept {
+        name!("test_promise_module");
+        version!("0.0.1");
+    }
+
+    impl PlannedPromiseType for Kept {
+        type Plan = ();
+
+        fn check_plan(&mut self, _promiser: &str, _attributes: &Attributes) -> (CheckResult, ()) {
+            (CheckResult::Kept, ())
+        }
+
+        fn apply_plan(
+            &mut self,
+            _promiser: &str,
+            _attributes: &Attributes,
+            _plan: Option<()>,
+        ) -> ApplyResult {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn it_drops_unused_plans() {
+        let mut promise = Planned::new(Kept {});
+        promise.check("/tmp/test", &Attributes::default());
+        assert!(promise.plans.is_empty());
+
+        // Audited promises are not applied
+        let mut promise = Planned::new(Lines {});
+        let audit = Executor::new().warn_only(true);
+        assert_eq!(
+            audit.evaluate_once(&mut promise, "/tmp/test", &Attributes::default()),
+            crate::EvaluateOutcome::NotKept
+        );
+        promise.set_context(&PromiseContext::default());
+        assert!(promise.plans.is_empty());
+    }
+
+    #[test]
+    fn it_passes_plans_to_apply() {
+        let mut promise = Planned::new(Lines {});
+        assert_eq!(
+            Executor::new().evaluate_once(&mut promise, "/tmp/test", &Attributes::default()),
+            crate::EvaluateOutcome::Repaired
+        );
+        assert!(promise.plans.is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+use std::fmt;
+
+/// Operating system family a promise type can run on
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+    FreeBsd,
+    OpenBsd,
+    NetBsd,
+    /// Solaris and illumos
+    Solaris,
+    Aix,
+}
+
+impl Platform {
+    /// Platform the module is running on, `None` if it is not listed
+    pub fn current() -> Option<Self> {
+        if cfg!(target_os = "linux") {
+            Some(Platform::Linux)
+        } else if cfg!(target_os = "macos") {
+            Some(Platform::MacOs)
+        } else if cfg!(target_os = "windows") {
+            Some(Platform::Windows)
+        } else if cfg!(target_os = "freebsd") {
+            Some(Platform::FreeBsd)
+        } else if cfg!(target_os = "openbsd") {
+            Some(Platform::OpenBsd)
+        } else if cfg!(target_os = "netbsd") {
+            Some(Platform::NetBsd)
+        } else if cfg!(any(target_os = "solaris", target_os = "illumos")) {
+            Some(Platform::Solaris)
+        } else if cfg!(target_os = "aix") {
+            Some(Platform::Aix)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the current platform is part of the given ones
+    ///
+    /// An empty list means every platform is supported.
+    pub(crate) fn is_supported(platforms: &[Platform]) -> bool {
+        platforms.is_empty() || Self::current().is_some_and(|p| platforms.contains(&p))
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Platform::Linux => "Linux",
+            Platform::MacOs => "macOS",
+            Platform::Windows => "Windows",
+            Platform::FreeBsd => "FreeBSD",
+            Platform::OpenBsd => "OpenBSD",
+            Platform::NetBsd => "NetBSD",
+            Platform::Solaris => "Solaris",
+            Platform::Aix => "AIX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checks_supported_platforms() {
+        assert!(Platform::is_supported(&[]));
+        assert_eq!(
+            Platform::is_supported(&[Platform::Linux]),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            Platform::is_supported(&[Platform::Windows, Platform::Aix]),
+            cfg!(any(target_os = "windows", target_os = "aix"))
+        );
+    }
+}
@@ -1,12 +1,15 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2021 Normation SAS
 
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
-use crate::{error, info, log::LevelFilter};
+use crate::{
+    attribute::Attributes,
+    log::{Level, LevelFilter},
+};
 
 const ALLOWED_CHAR_CLASS: &str = "_0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 
@@ -26,27 +29,137 @@ impl Class {
         }
         Self { inner }
     }
+
+    /// Build a class name from any string, replacing unexpected chars by `_`
+    /// like CFEngine's `canonify`
+    pub fn canonify<S: AsRef<str>>(s: S) -> Self {
+        let inner = s
+            .as_ref()
+            .chars()
+            .map(|c| {
+                if ALLOWED_CHAR_CLASS.contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        Self { inner }
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+/// Kind of change made to the system
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// Record of a change made to the system
+///
+/// Attached to a `Repair`, and sent in the evaluate response
+/// when the agent supports it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// What was changed, like a file path or a configuration key
+    pub target: String,
+    /// Previous value, `None` if created or unknown
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub before: Option<Value>,
+    /// New value, `None` if removed or unknown
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub after: Option<Value>,
+}
+
+impl Change {
+    pub fn new<S: Into<String>>(
+        kind: ChangeKind,
+        target: S,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) -> Self {
+        Self {
+            kind,
+            target: target.into(),
+            before,
+            after,
+        }
+    }
+
+    pub fn created<S: Into<String>, V: Into<Value>>(target: S, after: V) -> Self {
+        Self::new(ChangeKind::Created, target, None, Some(after.into()))
+    }
+
+    pub fn modified<S: Into<String>, V: Into<Value>>(target: S, before: V, after: V) -> Self {
+        Self::new(
+            ChangeKind::Modified,
+            target,
+            Some(before.into()),
+            Some(after.into()),
+        )
+    }
+
+    pub fn removed<S: Into<String>, V: Into<Value>>(target: S, before: V) -> Self {
+        Self::new(ChangeKind::Removed, target, Some(before.into()), None)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Display strings without JSON quotes
+        let display = |v: &Value| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        match (self.kind, &self.before, &self.after) {
+            (ChangeKind::Created, _, Some(after)) => {
+                write!(f, "{}: added '{}'", self.target, display(after))
+            }
+            (ChangeKind::Created, _, None) => write!(f, "{}: added", self.target),
+            (ChangeKind::Modified, Some(before), Some(after)) => write!(
+                f,
+                "{}: changed '{}' to '{}'",
+                self.target,
+                display(before),
+                display(after)
+            ),
+            (ChangeKind::Modified, _, _) => write!(f, "{}: changed", self.target),
+            (ChangeKind::Removed, Some(before), _) => {
+                write!(f, "{}: removed '{}'", self.target, display(before))
+            }
+            (ChangeKind::Removed, None, _) => write!(f, "{}: removed", self.target),
+        }
+    }
+}
+
+/// What to do with promises that are not kept
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
 #[serde(rename_all = "lowercase")]
 //#[serde(default)]
-pub(crate) enum ActionPolicy {
-    #[serde(alias = "nop")]
+pub enum ActionPolicy {
+    /// Only check, never make changes, and report what would be changed
     Warn,
+    /// Only check and report the outcome, without logging what would be changed
+    Nop,
+    /// Make changes
+    #[default]
     Fix,
 }
 
-impl Default for ActionPolicy {
-    fn default() -> Self {
-        ActionPolicy::Fix
+impl ActionPolicy {
+    /// Parse the `action_policy` attribute, defaulting to `Warn` for unknown values
+    pub(crate) fn from_value(value: &Value) -> Self {
+        serde_json::from_value(value.clone()).unwrap_or(ActionPolicy::Warn)
     }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 /// Promise validation outcomes
-pub(crate) enum ValidateOutcome {
+pub enum ValidateOutcome {
     /// Validation successful
     Valid,
     /// Validation failed, error in cfengine policy
@@ -60,6 +173,10 @@ pub(crate) enum ValidateOutcome {
 pub enum ValidateResult {
     /// Validation successful
     Valid,
+    /// Validation successful, but the policy should probably be fixed
+    ///
+    /// Each message will be logged at warning level
+    ValidWithWarnings(Vec<String>),
     /// Validation failed, error in cfengine policy
     ///
     /// Parameter will be logged at error level
@@ -74,6 +191,12 @@ impl ValidateResult {
     pub(crate) fn outcome(&self) -> ValidateOutcome {
         match self {
             ValidateResult::Valid => ValidateOutcome::Valid,
+            ValidateResult::ValidWithWarnings(w) => {
+                for warning in w {
+                    warning!("{}", warning);
+                }
+                ValidateOutcome::Valid
+            }
             ValidateResult::Invalid(e) => {
                 error!("{}", e);
                 ValidateOutcome::Invalid
@@ -86,10 +209,12 @@ impl ValidateResult {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 /// Promise evaluation outcomes
-pub(crate) enum EvaluateOutcome {
+///
+/// Ordered from best to worst.
+pub enum EvaluateOutcome {
     /// Satisfied already, no change
     Kept,
     /// Not satisfied before, but fixed
@@ -100,6 +225,62 @@ pub(crate) enum EvaluateOutcome {
     Error,
 }
 
+/// Details of a repair, for `ApplyResult::RepairedWith`
+///
+/// The message is logged at info level, followed by the additional messages at their level.
+/// Changes are sent to the agent when it supports it, and logged at info level otherwise.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Repair {
+    message: String,
+    messages: Vec<(Level, String)>,
+    changes: Vec<Change>,
+    noop: bool,
+}
+
+impl Repair {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            messages: vec![],
+            changes: vec![],
+            noop: false,
+        }
+    }
+
+    /// Log an additional message at the given level
+    pub fn message<S: Into<String>>(mut self, level: Level, message: S) -> Self {
+        self.messages.push((level, message.into()));
+        self
+    }
+
+    /// Record a change made to the system
+    pub fn change(mut self, change: Change) -> Self {
+        self.changes.push(change);
+        self
+    }
+
+    /// Record changes made to the system
+    pub fn changes(mut self, changes: Vec<Change>) -> Self {
+        self.changes.extend(changes);
+        self
+    }
+
+    /// Mark the repair as making no effective change
+    ///
+    /// For cosmetic repairs, like rewriting a file with the same content, to tell
+    /// churn from real drift. The message is marked as a no-op. Defaults to `false`.
+    pub fn noop(mut self, noop: bool) -> Self {
+        self.noop = noop;
+        self
+    }
+}
+
+impl From<Repair> for ApplyResult {
+    fn from(repair: Repair) -> Self {
+        ApplyResult::RepairedWith(repair)
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 /// Promise application result
 pub enum ApplyResult {
@@ -117,11 +298,47 @@ pub enum ApplyResult {
     ///
     /// Parameter will be logged at critical level
     Error(String),
+    /// Not satisfied before, but fixed, with details of the repair
+    ///
+    /// See `Repair` for the logged messages and the changes sent to the agent.
+    RepairedWith(Repair),
+    /// Not satisfied before, not fixed, with several messages
+    ///
+    /// Allows reporting changes made before failing. Each message will be logged at its level
+    NotKeptMultiple(Vec<(Level, String)>),
+    /// Items of a list processed separately, like the elements of an slist
+    ///
+    /// Repaired items are logged at info level, and failed ones with their error. Any failure
+    /// makes the promise not kept and is logged at error level, unless `best_effort` is set:
+    /// then it is repaired if at least one item was, with failures logged as warnings.
+    Partial {
+        repaired: Vec<String>,
+        failed: Vec<(String, String)>,
+        best_effort: bool,
+    },
+    /// Not satisfied before, changes were started but could not be completed
+    ///
+    /// The executor will call `rollback` to undo the partial changes.
+    ///
+    /// Parameter will be logged at error level
+    PartiallyApplied(String),
     /// A promise that should never be applied but only checked
+    ///
+    /// The outcome of `check` is reported, without error.
+    ///
+    /// Prefer declaring `ActionPolicy::Warn` as `default_action_policy` for audit-only promise types.
     AuditOnly,
 }
 
 impl ApplyResult {
+    /// Changes recorded in the result
+    pub(crate) fn changes(&self) -> Vec<Change> {
+        match self {
+            ApplyResult::RepairedWith(repair) => repair.changes.clone(),
+            _ => vec![],
+        }
+    }
+
     pub(crate) fn outcome(&self) -> EvaluateOutcome {
         match self {
             ApplyResult::Kept => EvaluateOutcome::Kept,
@@ -137,10 +354,53 @@ impl ApplyResult {
                 error!("{}", e);
                 EvaluateOutcome::Error
             }
-            ApplyResult::AuditOnly => {
-                error!("Should not be applied, audit only promise");
-                EvaluateOutcome::Error
+            ApplyResult::RepairedWith(repair) => {
+                if repair.noop {
+                    info!("{} (no effective change)", repair.message);
+                } else {
+                    info!("{}", repair.message);
+                }
+                for (level, message) in &repair.messages {
+                    log!(*level, "{}", message);
+                }
+                EvaluateOutcome::Repaired
+            }
+            ApplyResult::NotKeptMultiple(logs) => {
+                for (level, message) in logs {
+                    log!(*level, "{}", message);
+                }
+                EvaluateOutcome::NotKept
             }
+            ApplyResult::Partial {
+                repaired,
+                failed,
+                best_effort,
+            } => {
+                for item in repaired {
+                    info!("{}", item);
+                }
+                let accepted = *best_effort && !repaired.is_empty();
+                let level = if accepted {
+                    Level::Warning
+                } else {
+                    Level::Error
+                };
+                for (item, error) in failed {
+                    log!(level, "{}: {}", item, error);
+                }
+                match (repaired.is_empty(), failed.is_empty()) {
+                    (true, true) => EvaluateOutcome::Kept,
+                    (false, true) => EvaluateOutcome::Repaired,
+                    _ if accepted => EvaluateOutcome::Repaired,
+                    _ => EvaluateOutcome::NotKept,
+                }
+            }
+            ApplyResult::PartiallyApplied(e) => {
+                error!("{}", e);
+                EvaluateOutcome::NotKept
+            }
+            // Nothing was changed, the executor reports the check outcome
+            ApplyResult::AuditOnly => EvaluateOutcome::NotKept,
         }
     }
 }
@@ -166,8 +426,8 @@ pub enum CheckResult {
 }
 
 impl CheckResult {
-    /// If is_check_only, not kept is logged as error, else as info
-    pub(crate) fn outcome(&self, is_check_only: bool) -> EvaluateOutcome {
+    /// Not kept is logged at the given level, as it depends on the action policy
+    pub(crate) fn outcome(&self, not_kept_level: Level) -> EvaluateOutcome {
         match self {
             CheckResult::Kept => EvaluateOutcome::Kept,
             CheckResult::AlwaysApply => {
@@ -175,11 +435,7 @@ impl CheckResult {
                 EvaluateOutcome::NotKept
             }
             CheckResult::NotKept(e) => {
-                if is_check_only {
-                    error!("{}", e);
-                } else {
-                    info!("{}", e);
-                }
+                log!(not_kept_level, "{}", e);
                 EvaluateOutcome::NotKept
             }
             CheckResult::Error(e) => {
@@ -193,7 +449,7 @@ impl CheckResult {
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 /// Result for init/terminate
-pub(crate) enum ProtocolOutcome {
+pub enum ProtocolOutcome {
     /// Success
     Success,
     /// Error
@@ -258,10 +514,14 @@ pub(crate) struct ValidateRequest {
     operation: ValidateOperation,
     pub(crate) log_level: LevelFilter,
     pub(crate) promiser: String,
-    pub(crate) attributes: Map<String, Value>,
+    pub(crate) attributes: Attributes,
     pub(crate) promise_type: String,
     pub(crate) filename: PathBuf,
     pub(crate) line_number: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bundle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) namespace: Option<String>,
     //pub(crate) action_policy: ActionPolicy,
 }
 
@@ -271,13 +531,53 @@ pub(crate) struct EvaluateRequest {
     operation: EvaluateOperation,
     pub(crate) log_level: LevelFilter,
     pub(crate) promiser: String,
-    pub(crate) attributes: Map<String, Value>,
+    pub(crate) attributes: Attributes,
     pub(crate) promise_type: String,
     pub(crate) filename: PathBuf,
     pub(crate) line_number: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bundle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) namespace: Option<String>,
     //pub(crate) action_policy: ActionPolicy,
 }
 
+impl ValidateRequest {
+    pub(crate) fn context(&self) -> PromiseContext {
+        PromiseContext {
+            promise_type: self.promise_type.clone(),
+            filename: self.filename.clone(),
+            line_number: self.line_number,
+            bundle: self.bundle.clone(),
+            namespace: self.namespace.clone(),
+        }
+    }
+}
+
+impl EvaluateRequest {
+    pub(crate) fn context(&self) -> PromiseContext {
+        PromiseContext {
+            promise_type: self.promise_type.clone(),
+            filename: self.filename.clone(),
+            line_number: self.line_number,
+            bundle: self.bundle.clone(),
+            namespace: self.namespace.clone(),
+        }
+    }
+}
+
+/// Location of the promise being handled in the policy
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PromiseContext {
+    pub promise_type: String,
+    pub filename: PathBuf,
+    pub line_number: u16,
+    /// Bundle containing the promise, when sent by the agent
+    pub bundle: Option<String>,
+    /// Namespace of the bundle, when sent by the agent
+    pub namespace: Option<String>,
+}
+
 // {"operation": "terminate", "log_level": "info"}
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub(crate) struct TerminateRequest {
@@ -291,18 +591,78 @@ pub(crate) struct TerminateRequest {
 pub(crate) struct ValidateResponse {
     operation: ValidateOperation,
     promiser: String,
-    attributes: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<Attributes>,
     result: ValidateOutcome,
 }
 
 impl ValidateResponse {
-    pub(crate) fn new(request: &ValidateRequest, result: ValidateOutcome) -> Self {
-        Self {
+    /// Response to a request, with an `Error` outcome until set
+    pub(crate) fn builder(request: &ValidateRequest) -> ValidateResponseBuilder {
+        ValidateResponseBuilder(Self {
             operation: ValidateOperation::ValidatePromise,
             promiser: request.promiser.clone(),
-            result,
-            attributes: request.attributes.clone(),
-        }
+            attributes: Some(request.attributes.clone()),
+            result: ValidateOutcome::Error,
+        })
+    }
+}
+
+pub(crate) struct ValidateResponseBuilder(ValidateResponse);
+
+impl ValidateResponseBuilder {
+    pub(crate) fn outcome(mut self, outcome: ValidateOutcome) -> Self {
+        self.0.result = outcome;
+        self
+    }
+
+    /// Hide the values of secret attributes
+    pub(crate) fn redact(mut self, secrets: &[String]) -> Self {
+        self.0.attributes = self.0.attributes.map(|a| a.redacted(secrets));
+        self
+    }
+
+    /// Omit or truncate the echoed attributes
+    pub(crate) fn echo(mut self, enabled: bool, max_value_bytes: Option<usize>) -> Self {
+        self.0.attributes = echoed(self.0.attributes, enabled, max_value_bytes);
+        self
+    }
+
+    /// Only echo the given attributes, if any
+    pub(crate) fn only(mut self, declared: Option<&[String]>) -> Self {
+        self.0.attributes = self.0.attributes.map(|a| only(a, declared));
+        self
+    }
+
+    pub(crate) fn build(self) -> ValidateResponse {
+        self.0
+    }
+}
+
+/// Attributes without the undeclared ones, if a declaration is given
+fn only(attributes: Attributes, declared: Option<&[String]>) -> Attributes {
+    match declared {
+        Some(declared) => Attributes::from(
+            attributes
+                .into_inner()
+                .into_iter()
+                .filter(|(k, _)| declared.contains(k))
+                .collect::<Map<String, Value>>(),
+        ),
+        None => attributes,
+    }
+}
+
+/// Echoed attributes, omitted or truncated
+fn echoed(
+    attributes: Option<Attributes>,
+    enabled: bool,
+    max_value_bytes: Option<usize>,
+) -> Option<Attributes> {
+    match (enabled, max_value_bytes) {
+        (false, _) => None,
+        (true, Some(max)) => attributes.map(|a| a.truncated(max)),
+        (true, None) => attributes,
     }
 }
 
@@ -311,24 +671,68 @@ impl ValidateResponse {
 pub(crate) struct EvaluateResponse {
     operation: EvaluateOperation,
     promiser: String,
-    attributes: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<Attributes>,
     result: EvaluateOutcome,
     result_classes: Vec<Class>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    changes: Vec<Change>,
 }
 
 impl EvaluateResponse {
-    pub(crate) fn new(
-        request: &EvaluateRequest,
-        result: EvaluateOutcome,
-        classes: Vec<Class>,
-    ) -> Self {
-        Self {
+    /// Response to a request, with an `Error` outcome until set
+    pub(crate) fn builder(request: &EvaluateRequest) -> EvaluateResponseBuilder {
+        EvaluateResponseBuilder(Self {
             operation: EvaluateOperation::EvaluatePromise,
             promiser: request.promiser.clone(),
-            result,
-            attributes: request.attributes.clone(),
-            result_classes: classes,
-        }
+            attributes: Some(request.attributes.clone()),
+            result: EvaluateOutcome::Error,
+            result_classes: vec![],
+            changes: vec![],
+        })
+    }
+}
+
+pub(crate) struct EvaluateResponseBuilder(EvaluateResponse);
+
+impl EvaluateResponseBuilder {
+    pub(crate) fn outcome(mut self, outcome: EvaluateOutcome) -> Self {
+        self.0.result = outcome;
+        self
+    }
+
+    /// Classes to define in the agent
+    pub(crate) fn classes(mut self, classes: Vec<Class>) -> Self {
+        self.0.result_classes = classes;
+        self
+    }
+
+    /// Send the changes made to the agent
+    pub(crate) fn changes(mut self, changes: Vec<Change>) -> Self {
+        self.0.changes = changes;
+        self
+    }
+
+    /// Hide the values of secret attributes
+    pub(crate) fn redact(mut self, secrets: &[String]) -> Self {
+        self.0.attributes = self.0.attributes.map(|a| a.redacted(secrets));
+        self
+    }
+
+    /// Omit or truncate the echoed attributes
+    pub(crate) fn echo(mut self, enabled: bool, max_value_bytes: Option<usize>) -> Self {
+        self.0.attributes = echoed(self.0.attributes, enabled, max_value_bytes);
+        self
+    }
+
+    /// Only echo the given attributes, if any
+    pub(crate) fn only(mut self, declared: Option<&[String]>) -> Self {
+        self.0.attributes = self.0.attributes.map(|a| only(a, declared));
+        self
+    }
+
+    pub(crate) fn build(self) -> EvaluateResponse {
+        self.0
     }
 }
 
@@ -350,6 +754,8 @@ impl TerminateResponse {
 
 #[cfg(test)]
 mod tests {
+    use serde_json::Map;
+
     use super::*;
 
     #[test]
@@ -358,6 +764,149 @@ mod tests {
         let _c = Class::new("my class");
     }
 
+    #[test]
+    fn it_canonifies_classes() {
+        assert_eq!(
+            Class::canonify("/etc/my-file.conf"),
+            Class::new("_etc_my_file_conf")
+        );
+        assert_eq!(Class::canonify("café"), Class::new("caf_"));
+    }
+
+    #[test]
+    fn it_logs_validation_warnings() {
+        if !crate::log::compiled_in(Level::Warning) {
+            return;
+        }
+        let result = ValidateResult::ValidWithWarnings(vec!["insecure".to_string()]);
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            result.outcome()
+        });
+        assert_eq!(outcome, ValidateOutcome::Valid);
+        assert_eq!(logs, vec![(Level::Warning, "insecure".to_string())]);
+    }
+
+    #[test]
+    fn it_reports_partial_results() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let partial = |best_effort: bool| ApplyResult::Partial {
+            repaired: vec!["installed vim".to_string()],
+            failed: vec![("emacs".to_string(), "not found".to_string())],
+            best_effort,
+        };
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            partial(false).outcome()
+        });
+        assert_eq!(outcome, EvaluateOutcome::NotKept);
+        assert_eq!(
+            logs,
+            vec![
+                (Level::Info, "installed vim".to_string()),
+                (Level::Error, "emacs: not found".to_string())
+            ]
+        );
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            partial(true).outcome()
+        });
+        assert_eq!(outcome, EvaluateOutcome::Repaired);
+        assert!(logs.contains(&(Level::Warning, "emacs: not found".to_string())));
+    }
+
+    #[test]
+    fn it_logs_noop_repairs() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let result = ApplyResult::from(
+            Repair::new("rewrote /etc/motd")
+                .noop(true)
+                .change(Change::modified("/etc/motd", "hello", "hello")),
+        );
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            result.outcome()
+        });
+        assert_eq!(outcome, EvaluateOutcome::Repaired);
+        assert_eq!(
+            logs,
+            vec![(
+                Level::Info,
+                "rewrote /etc/motd (no effective change)".to_string()
+            )]
+        );
+        assert_eq!(result.changes().len(), 1);
+    }
+
+    #[test]
+    fn it_logs_repair_messages_at_their_level() {
+        if !crate::log::compiled_in(Level::Info) {
+            return;
+        }
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            ApplyResult::from(
+                Repair::new("restarted sshd")
+                    .message(Level::Warning, "configuration reloaded twice")
+                    .message(Level::Verbose, "hidden at info level"),
+            )
+            .outcome()
+        });
+        assert_eq!(outcome, EvaluateOutcome::Repaired);
+        assert_eq!(
+            logs,
+            vec![
+                (Level::Info, "restarted sshd".to_string()),
+                (Level::Warning, "configuration reloaded twice".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_logs_not_kept_messages_at_their_level() {
+        if !crate::log::compiled_in(Level::Notice) {
+            return;
+        }
+        let (outcome, logs) = crate::log::capture(|| {
+            crate::log::set_max_level(LevelFilter::Info);
+            ApplyResult::NotKeptMultiple(vec![
+                (Level::Error, "could not write /etc/motd".to_string()),
+                (Level::Notice, "file is immutable".to_string()),
+                (Level::Debug, "hidden at info level".to_string()),
+            ])
+            .outcome()
+        });
+        assert_eq!(outcome, EvaluateOutcome::NotKept);
+        assert_eq!(
+            logs,
+            vec![
+                (Level::Error, "could not write /etc/motd".to_string()),
+                (Level::Notice, "file is immutable".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_serializes_changes() {
+        let change = Change::modified("/etc/ssh/sshd_config:Port", 22, 2222);
+        assert_eq!(
+            serde_json::to_string(&change).unwrap(),
+            r#"{"kind":"modified","target":"/etc/ssh/sshd_config:Port","before":22,"after":2222}"#
+        );
+        assert_eq!(
+            change.to_string(),
+            "/etc/ssh/sshd_config:Port: changed '22' to '2222'"
+        );
+        assert_eq!(
+            serde_json::to_string(&Change::created("/etc/motd", "hello")).unwrap(),
+            r#"{"kind":"created","target":"/etc/motd","after":"hello"}"#
+        );
+    }
+
     #[test]
     fn it_parses_requests() {
         let val = r#"{"filename":"/tmp/test.cf","line_number": 42,"promise_type":"git","attributes":{"repo":"https://github.com/cfengine/masterfiles"},"log_level":"info","operation":"validate_promise","promiser":"/tmp/masterfiles"}"#;
@@ -371,10 +920,12 @@ mod tests {
             operation: ValidateOperation::ValidatePromise,
             log_level: LevelFilter::Info,
             promiser: "/tmp/masterfiles".to_string(),
-            attributes,
+            attributes: attributes.into(),
             promise_type: "git".to_string(),
             filename: PathBuf::from("/tmp/test.cf"),
             line_number: 42,
+            bundle: None,
+            namespace: None,
             //action_policy: ActionPolicy::Fix,
         };
 
@@ -382,5 +933,29 @@ mod tests {
             serde_json::from_str::<ValidateRequest>(val).unwrap(),
             ref_val
         );
+
+        let val = r#"{"filename":"/tmp/test.cf","line_number":42,"promise_type":"git","attributes":{},"log_level":"info","operation":"validate_promise","promiser":"/tmp/masterfiles","bundle":"main","namespace":"default"}"#;
+        let context = serde_json::from_str::<ValidateRequest>(val)
+            .unwrap()
+            .context();
+        assert_eq!(context.bundle.as_deref(), Some("main"));
+        assert_eq!(context.namespace.as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn it_builds_evaluate_responses() {
+        let val = r#"{"filename":"/tmp/test.cf","line_number":42,"promise_type":"file","attributes":{"token":"s3cr3t"},"log_level":"info","operation":"evaluate_promise","promiser":"/etc/motd"}"#;
+        let request = serde_json::from_str::<EvaluateRequest>(val).unwrap();
+        let response = EvaluateResponse::builder(&request)
+            .outcome(EvaluateOutcome::Repaired)
+            .classes(vec![Class::new("motd_repaired")])
+            .changes(vec![Change::created("/etc/motd", "hello")])
+            .redact(&["token".to_string()])
+            .echo(true, None)
+            .build();
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"operation":"evaluate_promise","promiser":"/etc/motd","attributes":{"token":"***"},"result":"repaired","result_classes":["motd_repaired"],"changes":[{"kind":"created","target":"/etc/motd","after":"hello"}]}"#
+        );
     }
 }
@@ -0,0 +1,587 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Several promise types served by a single module
+
+use serde_json::Value;
+
+use crate::{
+    asynchronous::Blocking,
+    planned::{Planned, PlannedPromiseType},
+    ActionPolicy, ApplyResult, AsyncPromiseType, AttributeType, Attributes, Capabilities,
+    CheckResult, InitSettings, Platform, Progress, PromiseContext, PromiseType, ProtocolResult,
+    ResourceKind, ValidateResult,
+};
+
+/// Promise types of a module, routed by the `promise_type` of each request
+///
+/// Usually built with `promise_registry!`, and run like any promise type. Methods
+/// not related to a request, like `init` or `terminate`, are called on every promise type
+/// supported on the current platform. Each promise type gets its own state directory,
+/// named after it in the registry's one.
+pub struct PromiseRegistry {
+    name: &'static str,
+    version: &'static str,
+    promise_types: Vec<(String, Box<dyn PromiseType>)>,
+    /// Promise type of the current request
+    current: Option<usize>,
+    /// Requested promise type, kept for error messages
+    requested: String,
+}
+
+impl PromiseRegistry {
+    /// Registry advertised as `name` and `version` in the module header
+    pub fn new(name: &'static str, version: &'static str) -> Self {
+        Self {
+            name,
+            version,
+            promise_types: vec![],
+            current: None,
+            requested: String::new(),
+        }
+    }
+
+    /// Serve promises of type `promise_type` with the given implementation
+    ///
+    /// Panics if `promise_type` is already registered.
+    pub fn register<T: PromiseType + 'static>(mut self, promise_type: &str, promise: T) -> Self {
+        assert!(
+            !self.promise_types.iter().any(|(t, _)| t == promise_type),
+            "promise type {} is registered twice",
+            promise_type
+        );
+        self.promise_types
+            .push((promise_type.to_string(), Box::new(promise)));
+        self
+    }
+
+    /// Serve promises of type `promise_type` with the given async implementation
+    ///
+    /// Each method's future is run to completion, like with `Executor::run_async`.
+    pub fn register_async<T: AsyncPromiseType + 'static>(
+        self,
+        promise_type: &str,
+        promise: T,
+    ) -> Self {
+        self.register(promise_type, Blocking::new(promise))
+    }
+
+    /// Serve promises of type `promise_type` with the given planned implementation
+    ///
+    /// The plan returned by each check is passed to the following apply, like with
+    /// `Executor::run_planned`.
+    pub fn register_planned<T: PlannedPromiseType + 'static>(
+        self,
+        promise_type: &str,
+        promise: T,
+    ) -> Self {
+        self.register(promise_type, Planned::new(promise))
+    }
+
+    /// Names of the registered promise types
+    pub fn promise_types(&self) -> Vec<&str> {
+        self.promise_types.iter().map(|(t, _)| t.as_str()).collect()
+    }
+
+    fn current(&self) -> Option<&dyn PromiseType> {
+        self.current.map(|i| self.promise_types[i].1.as_ref())
+    }
+
+    fn current_mut(&mut self) -> Option<&mut Box<dyn PromiseType>> {
+        self.current.map(|i| &mut self.promise_types[i].1)
+    }
+
+    fn unsupported(&self) -> String {
+        format!("Unsupported promise type {}", self.requested)
+    }
+
+    /// Run a method on all supported promise types, stopping at the first failure
+    fn all<F: FnMut(&str, &mut dyn PromiseType) -> ProtocolResult>(
+        &mut self,
+        mut f: F,
+    ) -> ProtocolResult {
+        for (name, promise) in self.promise_types.iter_mut() {
+            if !Platform::is_supported(&promise.supported_platforms()) {
+                continue;
+            }
+            match f(name, promise.as_mut()) {
+                ProtocolResult::Success => (),
+                ProtocolResult::Failure(e) => {
+                    return ProtocolResult::Failure(format!("{}: {}", name, e))
+                }
+                ProtocolResult::Error(e) => {
+                    return ProtocolResult::Error(format!("{}: {}", name, e))
+                }
+            }
+        }
+        ProtocolResult::Success
+    }
+}
+
+/// Build a `PromiseRegistry` named after the current crate
+///
+/// Entries can be conditionally compiled:
+///
+/// ```ignore
+/// let registry = promise_registry![
+///     "directory" => Directory {},
+///     #[cfg(feature = "git")]
+///     "git" => Git::default(),
+/// ];
+/// Executor::new().run(registry)
+/// ```
+#[macro_export]
+macro_rules! promise_registry {
+    ($($(#[$meta:meta])* $promise_type:literal => $promise:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut registry =
+            $crate::PromiseRegistry::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        $(
+            $(#[$meta])*
+            {
+                registry = registry.register($promise_type, $promise);
+            }
+        )*
+        registry
+    }};
+}
+
+impl PromiseType for PromiseRegistry {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn version(&self) -> &'static str {
+        self.version
+    }
+
+    fn kind(&self) -> ResourceKind {
+        self.current().map(|p| p.kind()).unwrap_or_default()
+    }
+
+    fn default_action_policy(&self) -> ActionPolicy {
+        self.current()
+            .map(|p| p.default_action_policy())
+            .unwrap_or(ActionPolicy::Fix)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.promise_types
+            .iter()
+            .fold(Capabilities::empty(), |c, (_, p)| c | p.capabilities())
+    }
+
+    fn supported_platforms(&self) -> Vec<Platform> {
+        self.current()
+            .map(|p| p.supported_platforms())
+            .unwrap_or_default()
+    }
+
+    fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.current()
+            .map(|p| p.required_attributes())
+            .unwrap_or_default()
+    }
+
+    fn optional_attributes(&self) -> Vec<(String, AttributeType)> {
+        self.current()
+            .map(|p| p.optional_attributes())
+            .unwrap_or_default()
+    }
+
+    fn exclusive_groups(&self) -> Vec<Vec<String>> {
+        self.current()
+            .map(|p| p.exclusive_groups())
+            .unwrap_or_default()
+    }
+
+    fn required_groups(&self) -> Vec<Vec<String>> {
+        self.current()
+            .map(|p| p.required_groups())
+            .unwrap_or_default()
+    }
+
+    fn conditional_requirements(&self) -> Vec<(String, Value, String)> {
+        self.current()
+            .map(|p| p.conditional_requirements())
+            .unwrap_or_default()
+    }
+
+    // Secrets are used to redact raw requests, before routing
+    fn secret_attributes(&self) -> Vec<String> {
+        self.promise_types
+            .iter()
+            .flat_map(|(_, p)| p.secret_attributes())
+            .collect()
+    }
+
+    fn deprecated_attributes(&self) -> Vec<(String, String)> {
+        self.current()
+            .map(|p| p.deprecated_attributes())
+            .unwrap_or_default()
+    }
+
+    fn attribute_aliases(&self) -> Vec<(String, String)> {
+        self.current()
+            .map(|p| p.attribute_aliases())
+            .unwrap_or_default()
+    }
+
+    fn attribute_defaults(&self) -> Vec<(String, Value)> {
+        self.current()
+            .map(|p| p.attribute_defaults())
+            .unwrap_or_default()
+    }
+
+    fn init(&mut self) -> ProtocolResult {
+        self.all(|_, p| p.init())
+    }
+
+    fn init_with_settings(&mut self, settings: &InitSettings) -> ProtocolResult {
+        self.all(|name, p| {
+            let settings = InitSettings {
+                state_dir: settings.state_dir.join(name),
+                ..settings.clone()
+            };
+            p.init_with_settings(&settings)
+        })
+    }
+
+    fn set_context(&mut self, context: &PromiseContext) {
+        self.requested = context.promise_type.clone();
+        self.current = self
+            .promise_types
+            .iter()
+            .position(|(t, _)| *t == context.promise_type);
+        if let Some(p) = self.current_mut() {
+            p.set_context(context);
+        }
+    }
+
+    fn expand_promiser(&self, promiser: &str) -> Vec<String> {
+        match self.current() {
+            Some(p) => p.expand_promiser(promiser),
+            None => vec![promiser.to_string()],
+        }
+    }
+
+    fn validate_promiser(&self, promiser: &str) -> ValidateResult {
+        match self.current() {
+            Some(p) => p.validate_promiser(promiser),
+            None => ValidateResult::Invalid(self.unsupported()),
+        }
+    }
+
+    fn validate(&self, promiser: &str, attributes: &Attributes) -> ValidateResult {
+        match self.current() {
+            Some(p) => p.validate(promiser, attributes),
+            None => ValidateResult::Invalid(self.unsupported()),
+        }
+    }
+
+    fn check(&mut self, promiser: &str, attributes: &Attributes) -> CheckResult {
+        let error = self.unsupported();
+        match self.current_mut() {
+            Some(p) => p.check(promiser, attributes),
+            None => CheckResult::Error(error),
+        }
+    }
+
+    fn plan(&self, promiser: &str, attributes: &Attributes) -> Vec<String> {
+        self.current()
+            .map(|p| p.plan(promiser, attributes))
+            .unwrap_or_default()
+    }
+
+    fn apply(&mut self, promiser: &str, attributes: &Attributes) -> ApplyResult {
+        let error = self.unsupported();
+        match self.current_mut() {
+            Some(p) => p.apply(promiser, attributes),
+            None => ApplyResult::Error(error),
+        }
+    }
+
+    fn apply_with_progress(
+        &mut self,
+        promiser: &str,
+        attributes: &Attributes,
+        progress: &mut Progress<'_>,
+    ) -> ApplyResult {
+        let error = self.unsupported();
+        match self.current_mut() {
+            Some(p) => p.apply_with_progress(promiser, attributes, progress),
+            None => ApplyResult::Error(error),
+        }
+    }
+
+    fn apply_batch(
+        &mut self,
+        promises: &[(String, Attributes)],
+        progress: &mut Progress<'_>,
+    ) -> Vec<ApplyResult> {
+        let error = self.unsupported();
+        match self.current_mut() {
+            Some(p) => p.apply_batch(promises, progress),
+            None => promises
+                .iter()
+                .map(|_| ApplyResult::Error(error.clone()))
+                .collect(),
+        }
+    }
+
+    fn rollback(&mut self, promiser: &str, attributes: &Attributes) -> ProtocolResult {
+        let error = self.unsupported();
+        match self.current_mut() {
+            Some(p) => p.rollback(promiser, attributes),
+            None => ProtocolResult::Error(error),
+        }
+    }
+
+    fn terminate(&mut self) -> ProtocolResult {
+        self.all(|_, p| p.terminate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        name,
+        testing::{Response, TestSession},
+        version, EvaluateOutcome, Executor,
+    };
+
+    struct File {}
+
+    impl PromiseType for File {
+        name!("file");
+        version!("0.0.1");
+
+        fn required_attributes(&self) -> Vec<(String, AttributeType)> {
+            vec![("mode".to_string(), AttributeType::FileMode)]
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::Kept
+        }
+    }
+
+    struct Service {}
+
+    impl PromiseType for Service {
+        name!("service");
+        version!("0.0.1");
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("stopped".to_string())
+        }
+
+        fn apply(&mut self, promiser: &str, _attributes: &Attributes) -> ApplyResult {
+            ApplyResult::Repaired(format!("started {}", promiser))
+        }
+    }
+
+    struct Ping {}
+
+    impl AsyncPromiseType for Ping {
+        fn name(&self) -> &'static str {
+            "ping"
+        }
+
+        fn version(&self) -> &'static str {
+            "0.0.1"
+        }
+
+        async fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::Kept
+        }
+    }
+
+    struct Line {}
+
+    impl PromiseType for Line {
+        name!("line");
+        version!("0.0.1");
+    }
+
+    impl PlannedPromiseType for Line {
+        type Plan = String;
+
+        fn check_plan(
+            &mut self,
+            promiser: &str,
+            _attributes: &Attributes,
+        ) -> (CheckResult, String) {
+            (
+                CheckResult::NotKept("missing".to_string()),
+                format!("added {}", promiser),
+            )
+        }
+
+        fn apply_plan(
+            &mut self,
+            _promiser: &str,
+            _attributes: &Attributes,
+            plan: Option<String>,
+        ) -> ApplyResult {
+            ApplyResult::Repaired(plan.unwrap())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "promise type file is registered twice")]
+    fn it_rejects_duplicate_promise_types() {
+        promise_registry![
+            "file" => File {},
+            "file" => File {},
+        ];
+    }
+
+    #[test]
+    fn it_registers_async_and_planned_types() {
+        let registry = PromiseRegistry::new("module", "0.0.1")
+            .register_async("ping", Ping {})
+            .register_planned("line", Line {});
+        let exchange = TestSession::new()
+            .promise_type("ping")
+            .evaluate("/tmp/test", Attributes::default())
+            .promise_type("line")
+            .evaluate("/tmp/test", Attributes::default())
+            .run(&Executor::new(), registry)
+            .unwrap();
+        let results: Vec<EvaluateOutcome> = exchange
+            .responses
+            .into_iter()
+            .filter_map(|r| match r {
+                Response::EvaluatePromise { result, .. } => Some(result),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![EvaluateOutcome::Kept, EvaluateOutcome::Repaired]
+        );
+    }
+
+    #[test]
+    fn it_routes_requests_by_promise_type() {
+        let registry = promise_registry![
+            "file" => File {},
+            "service" => Service {},
+            // Never enabled
+            #[cfg(any())]
+            "package" => Service {},
+        ];
+        assert_eq!(registry.name(), "rudder_resource");
+        assert_eq!(registry.promise_types(), vec!["file", "service"]);
+
+        let request = |operation: &str, promise_type: &str, attributes: &str| {
+            format!(
+                r#"{{"operation":"{}","log_level":"info","promise_type":"{}","promiser":"/tmp/test","attributes":{},"filename":"/tmp/test.cf","line_number":42}}"#,
+                operation, promise_type, attributes
+            )
+        };
+        let input = format!(
+            "CFEngine 3.18.0 v1\n\n{}\n\n{}\n\n{}\n\n{}\n\n{}\n\n",
+            request("validate_promise", "service", r#"{"mode":"0644"}"#),
+            request("evaluate_promise", "file", r#"{"mode":"0644"}"#),
+            request("evaluate_promise", "service", "{}"),
+            request("validate_promise", "package", "{}"),
+            r#"{"operation":"terminate","log_level":"info"}"#
+        );
+        let output = Executor::new().run_with_input(registry, &input).unwrap();
+        let results: Vec<&str> = output
+            .split("\n\n")
+            .filter_map(|r| r.split(r#""result":""#).nth(1))
+            .map(|r| r.split('"').next().unwrap())
+            .collect();
+        assert_eq!(
+            results,
+            vec!["invalid", "kept", "repaired", "invalid", "success"]
+        );
+    }
+
+    #[derive(Default)]
+    struct Foreign {
+        state_dirs: Arc<Mutex<Vec<PathBuf>>>,
+        restricted: bool,
+    }
+
+    impl PromiseType for Foreign {
+        name!("foreign");
+        version!("0.0.1");
+
+        fn supported_platforms(&self) -> Vec<Platform> {
+            if self.restricted {
+                // Any other platform
+                vec![if Platform::current() == Some(Platform::Aix) {
+                    Platform::Solaris
+                } else {
+                    Platform::Aix
+                }]
+            } else {
+                vec![]
+            }
+        }
+
+        fn init_with_settings(&mut self, settings: &InitSettings) -> ProtocolResult {
+            self.state_dirs
+                .lock()
+                .unwrap()
+                .push(settings.state_dir.clone());
+            ProtocolResult::Success
+        }
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("not there".to_string())
+        }
+    }
+
+    #[test]
+    fn it_skips_unsupported_promise_types() {
+        if !crate::log::compiled_in(crate::log::Level::Info) {
+            return;
+        }
+        let state_dirs = Arc::new(Mutex::new(vec![]));
+        let foreign = |restricted| Foreign {
+            state_dirs: state_dirs.clone(),
+            restricted,
+        };
+        let registry = PromiseRegistry::new("module", "0.0.1")
+            .register("here", foreign(false))
+            .register("there", foreign(true));
+        let exchange = TestSession::new()
+            .promise_type("here")
+            .evaluate("/tmp/test", Attributes::default())
+            .promise_type("there")
+            .evaluate("/tmp/test", Attributes::default())
+            .run(&Executor::new().state_dir("/tmp/state"), registry)
+            .unwrap();
+        let results: Vec<EvaluateOutcome> = exchange
+            .responses
+            .iter()
+            .filter_map(|r| match r {
+                Response::EvaluatePromise { result, .. } => Some(*result),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![EvaluateOutcome::NotKept, EvaluateOutcome::Kept]
+        );
+        let logs = exchange.logs;
+        assert!(logs
+            .iter()
+            .any(|(_, m)| m == "Skipping /tmp/test on unsupported platform"));
+        // Only the supported promise type is initialized, in its own directory
+        assert_eq!(
+            *state_dirs.lock().unwrap(),
+            vec![PathBuf::from("/tmp/state/module/here")]
+        );
+    }
+}
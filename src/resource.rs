@@ -7,12 +7,22 @@ use serde::{Deserialize, Serialize};
 
 // TODO abstract way cfengine stuff
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+/// Kind of resource implemented by a promise type
+///
+/// Used by the executor to decide whether `check` and `apply` should be called.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
 pub enum ResourceKind {
-    // We can check and apply
+    /// We can check and apply
+    ///
+    /// `apply` is only called when `check` did not return `Kept`.
+    #[default]
     State,
-    // We're only able to check
+    /// We're only able to check
+    ///
+    /// `apply` is never called, even outside of warn-only mode.
     Check,
-    // We always apply
+    /// We always apply
+    ///
+    /// `check` is never called, and nothing can be done in warn-only mode.
     Action,
 }
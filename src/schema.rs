@@ -0,0 +1,351 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Minimal JSON Schema validation
+//!
+//! Supports the structural subset of the specification: `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+//! `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`,
+//! `exclusiveMaximum`, `allOf` and `anyOf`, as well as boolean schemas.
+//! Annotations (`title`, `description`, etc.) are ignored, and schemas using other
+//! keywords reject every value, to never under-validate.
+
+use serde_json::{Map, Value};
+
+/// Validation failure, with the JSON pointer to the failing value
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct SchemaError {
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+const KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "allOf",
+    "anyOf",
+];
+
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+/// Check a value against a schema
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<(), SchemaError> {
+    check_schema(schema, "")?;
+    validate_at(schema, value, "")
+}
+
+/// Check that a schema and its sub-schemas only use supported keywords
+///
+/// The error path points into the schema.
+pub(crate) fn check_schema(schema: &Value, path: &str) -> Result<(), SchemaError> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(s) => s,
+        _ => return fail(path, "invalid schema".to_string()),
+    };
+    for (keyword, value) in schema {
+        let path = format!("{}/{}", path, keyword);
+        if !KEYWORDS.contains(&keyword.as_str()) {
+            if ANNOTATIONS.contains(&keyword.as_str()) {
+                continue;
+            }
+            return fail(&path, format!("unsupported schema keyword {}", keyword));
+        }
+        match (keyword.as_str(), value) {
+            ("properties", Value::Object(properties)) => {
+                for (key, s) in properties {
+                    check_schema(s, &format!("{}/{}", path, key))?;
+                }
+            }
+            ("allOf" | "anyOf", Value::Array(schemas)) => {
+                for (i, s) in schemas.iter().enumerate() {
+                    check_schema(s, &format!("{}/{}", path, i))?;
+                }
+            }
+            ("items" | "additionalProperties", s) => check_schema(s, &path)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn fail<T>(path: &str, message: String) -> Result<T, SchemaError> {
+    Err(SchemaError {
+        path: if path.is_empty() {
+            "/".to_string()
+        } else {
+            path.to_string()
+        },
+        message,
+    })
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    let actual = type_name(value);
+    actual == name || (name == "number" && actual == "integer")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), SchemaError> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return fail(path, "no value is allowed".to_string()),
+        Value::Object(s) => s,
+        _ => return fail(path, "invalid schema".to_string()),
+    };
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(t) => t.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        if !types.iter().any(|t| has_type(value, t)) {
+            return fail(
+                path,
+                format!("expected {}, got {}", types.join(" or "), type_name(value)),
+            );
+        }
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return fail(
+                path,
+                format!("expected one of {}", Value::from(values.clone())),
+            );
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return fail(path, format!("expected {}", expected));
+        }
+    }
+
+    match value {
+        Value::Object(object) => validate_object(schema, object, path)?,
+        Value::Array(array) => {
+            check_bounds(schema, "minItems", "maxItems", array.len(), "items", path)?;
+            if let Some(items) = schema.get("items") {
+                for (i, item) in array.iter().enumerate() {
+                    validate_at(items, item, &format!("{}/{}", path, i))?;
+                }
+            }
+        }
+        Value::String(s) => check_bounds(
+            schema,
+            "minLength",
+            "maxLength",
+            s.chars().count(),
+            "characters",
+            path,
+        )?,
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            let bound = |k: &str| schema.get(k).and_then(|b| b.as_f64());
+            if let Some(min) = bound("minimum").filter(|m| n < *m) {
+                return fail(path, format!("expected at least {}", min));
+            }
+            if let Some(max) = bound("maximum").filter(|m| n > *m) {
+                return fail(path, format!("expected at most {}", max));
+            }
+            if let Some(min) = bound("exclusiveMinimum").filter(|m| n <= *m) {
+                return fail(path, format!("expected more than {}", min));
+            }
+            if let Some(max) = bound("exclusiveMaximum").filter(|m| n >= *m) {
+                return fail(path, format!("expected less than {}", max));
+            }
+        }
+        _ => (),
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for s in schemas {
+            validate_at(s, value, path)?;
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if !schemas.iter().any(|s| validate_at(s, value, path).is_ok()) {
+            return fail(
+                path,
+                "does not match any of the allowed schemas".to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+) -> Result<(), SchemaError> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for key in required.iter().filter_map(|k| k.as_str()) {
+            if !object.contains_key(key) {
+                return fail(path, format!("missing required property {}", key));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(|p| p.as_object());
+    for (key, value) in object {
+        let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+        match properties.and_then(|p| p.get(key)) {
+            Some(s) => validate_at(s, value, &path)?,
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    return fail(&path, "unexpected property".to_string());
+                }
+                Some(s) => validate_at(s, value, &path)?,
+                None => (),
+            },
+        }
+    }
+    Ok(())
+}
+
+fn check_bounds(
+    schema: &Map<String, Value>,
+    min_key: &str,
+    max_key: &str,
+    len: usize,
+    unit: &str,
+    path: &str,
+) -> Result<(), SchemaError> {
+    let bound = |k: &str| schema.get(k).and_then(|b| b.as_u64());
+    if let Some(min) = bound(min_key).filter(|m| (len as u64) < *m) {
+        return fail(
+            path,
+            format!("expected at least {} {}, got {}", min, unit, len),
+        );
+    }
+    if let Some(max) = bound(max_key).filter(|m| (len as u64) > *m) {
+        return fail(
+            path,
+            format!("expected at most {} {}, got {}", max, unit, len),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_validates_nested_values() {
+        let schema = json!({
+            "type": "object",
+            "required": ["servers"],
+            "additionalProperties": false,
+            "properties": {
+                "servers": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "required": ["host"],
+                        "properties": {
+                            "host": {"type": "string", "minLength": 1},
+                            "port": {"type": "integer", "minimum": 1, "maximum": 65535},
+                            "proto": {"enum": ["tcp", "udp"]}
+                        }
+                    }
+                }
+            }
+        });
+        let error = |value: Value| validate(&schema, &value).unwrap_err();
+
+        assert!(validate(&schema, &json!({"servers": [{"host": "a", "port": 22}]})).is_ok());
+        assert_eq!(
+            error(json!({"servers": [{"host": "a"}, {"host": "b", "port": "22"}]})),
+            SchemaError {
+                path: "/servers/1/port".to_string(),
+                message: "expected integer, got string".to_string(),
+            }
+        );
+        assert_eq!(error(json!({"servers": []})).path, "/servers");
+        assert_eq!(
+            error(json!({})).message,
+            "missing required property servers"
+        );
+        assert_eq!(
+            error(json!({"servers": [{"host": "a"}], "x": 1})).path,
+            "/x"
+        );
+        assert_eq!(
+            error(json!({"servers": [{"host": "a", "proto": "icmp"}]})).message,
+            "expected one of [\"tcp\",\"udp\"]"
+        );
+        assert_eq!(
+            error(json!({"servers": [{"host": "a", "port": 0}]})).message,
+            "expected at least 1"
+        );
+    }
+
+    #[test]
+    fn it_rejects_unsupported_keywords() {
+        let schema = json!({
+            "title": "Servers",
+            "type": "array",
+            "items": {"type": "string", "pattern": "^[a-z]+$"}
+        });
+        assert_eq!(
+            validate(&schema, &json!(["a"])).unwrap_err(),
+            SchemaError {
+                path: "/items/pattern".to_string(),
+                message: "unsupported schema keyword pattern".to_string(),
+            }
+        );
+        assert!(check_schema(&json!({"not": {"type": "null"}}), "").is_err());
+        assert!(check_schema(&json!({"properties": {"a": {"$ref": "#/b"}}}), "").is_err());
+        assert!(check_schema(&json!({"description": "any", "type": "string"}), "").is_ok());
+    }
+
+    #[test]
+    fn it_validates_combinations() {
+        let schema =
+            json!({"anyOf": [{"type": "string"}, {"type": "number", "exclusiveMinimum": 0}]});
+        assert!(validate(&schema, &json!("a")).is_ok());
+        assert!(validate(&schema, &json!(1.5)).is_ok());
+        assert!(validate(&schema, &json!(0)).is_err());
+        assert!(validate(&json!(true), &json!(null)).is_ok());
+        assert_eq!(validate(&json!(false), &json!(null)).unwrap_err().path, "/");
+    }
+}
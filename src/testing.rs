@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! In-memory test harness for promise types
+//!
+//! Enabled by the `testing` feature. A `TestSession` sends typed requests
+//! to a promise type through an `Executor`, and records the parsed responses
+//! and the logs.
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    log::{capture, Level, LevelFilter},
+    Attributes, Class, EvaluateOutcome, Executor, PromiseType, ProtocolOutcome, ValidateOutcome,
+};
+
+/// Response sent by the promise module
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Response {
+    ValidatePromise {
+        promiser: String,
+        #[serde(default)]
+        attributes: Attributes,
+        result: ValidateOutcome,
+    },
+    EvaluatePromise {
+        promiser: String,
+        #[serde(default)]
+        attributes: Attributes,
+        result: EvaluateOutcome,
+        result_classes: Vec<Class>,
+    },
+    Terminate {
+        result: ProtocolOutcome,
+    },
+}
+
+/// Recorded exchange with the promise module
+#[derive(Debug, PartialEq, Clone)]
+pub struct Exchange {
+    /// Header sent by the module
+    pub header: String,
+    /// Responses, in the order of the requests
+    pub responses: Vec<Response>,
+    /// Logs produced during the session
+    pub logs: Vec<(Level, String)>,
+}
+
+/// Builder for a session with a promise module
+///
+/// A terminate request is always sent at the end of the session.
+#[derive(Debug, Clone)]
+pub struct TestSession {
+    agent_header: String,
+    log_level: LevelFilter,
+    promise_type: String,
+    requests: Vec<String>,
+}
+
+impl Default for TestSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestSession {
+    pub fn new() -> Self {
+        Self {
+            agent_header: "CFEngine 3.18.0 v1".to_string(),
+            log_level: LevelFilter::Info,
+            promise_type: "test".to_string(),
+            requests: vec![],
+        }
+    }
+
+    /// Header sent by the agent, defaults to "CFEngine 3.18.0 v1"
+    pub fn agent_header<S: Into<String>>(mut self, header: S) -> Self {
+        self.agent_header = header.into();
+        self
+    }
+
+    /// Log level for the following requests, defaults to info
+    pub fn log_level(mut self, level: LevelFilter) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Promise type of the following requests, defaults to "test"
+    pub fn promise_type<S: Into<String>>(mut self, promise_type: S) -> Self {
+        self.promise_type = promise_type.into();
+        self
+    }
+
+    fn request(mut self, operation: &str, promiser: &str, attributes: Attributes) -> Self {
+        self.requests.push(
+            json!({
+                "operation": operation,
+                "log_level": self.log_level,
+                "promise_type": self.promise_type,
+                "promiser": promiser,
+                "attributes": attributes,
+                "filename": "/tmp/test.cf",
+                "line_number": 1,
+            })
+            .to_string(),
+        );
+        self
+    }
+
+    /// Add a validate request
+    pub fn validate(self, promiser: &str, attributes: Attributes) -> Self {
+        self.request("validate_promise", promiser, attributes)
+    }
+
+    /// Add an evaluate request
+    pub fn evaluate(self, promiser: &str, attributes: Attributes) -> Self {
+        self.request("evaluate_promise", promiser, attributes)
+    }
+
+    /// Raw input sent to the module
+    pub fn input(&self) -> String {
+        let terminate = json!({"operation": "terminate", "log_level": self.log_level});
+        let mut input = format!("{}\n\n", self.agent_header);
+        for request in &self.requests {
+            input.push_str(request);
+            input.push_str("\n\n");
+        }
+        input.push_str(&format!("{}\n\n", terminate));
+        input
+    }
+
+    /// Run the session and parse the module's output
+    pub fn run<T: PromiseType>(&self, executor: &Executor, promise: T) -> Result<Exchange, Error> {
+        let input = self.input();
+        let (output, logs) = capture(|| executor.run_with_input(promise, &input));
+        let output = output?;
+
+        let mut lines = output.split("\n\n").filter(|l| !l.is_empty());
+        let header = lines.next().unwrap_or_default().to_string();
+        let responses = lines
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Response>, _>>()?;
+        Ok(Exchange {
+            header,
+            responses,
+            logs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{name, version, ApplyResult, CheckResult};
+
+    struct Test {}
+
+    impl PromiseType for Test {
+        name!("test_promise_module");
+        version!("0.0.1");
+
+        fn check(&mut self, _promiser: &str, _attributes: &Attributes) -> CheckResult {
+            CheckResult::NotKept("not there".to_string())
+        }
+
+        fn apply(&mut self, promiser: &str, _attributes: &Attributes) -> ApplyResult {
+            ApplyResult::Repaired(format!("fixed {}", promiser))
+        }
+    }
+
+    #[test]
+    fn it_records_exchanges() {
+        if !crate::log::compiled_in(crate::log::Level::Info) {
+            return;
+        }
+        let exchange = TestSession::new()
+            .validate("/tmp/test", Attributes::default())
+            .evaluate("/tmp/test", Attributes::default())
+            .run(&Executor::new(), Test {})
+            .unwrap();
+
+        assert_eq!(exchange.header, "test_promise_module 0.0.1 v1 json_based");
+        assert_eq!(
+            exchange.responses,
+            vec![
+                Response::ValidatePromise {
+                    promiser: "/tmp/test".to_string(),
+                    attributes: Attributes::default(),
+                    result: ValidateOutcome::Valid,
+                },
+                Response::EvaluatePromise {
+                    promiser: "/tmp/test".to_string(),
+                    attributes: Attributes::default(),
+                    result: EvaluateOutcome::Repaired,
+                    result_classes: vec![],
+                },
+                Response::Terminate {
+                    result: ProtocolOutcome::Success,
+                },
+            ]
+        );
+        assert!(exchange
+            .logs
+            .contains(&(Level::Info, "fixed /tmp/test".to_string())));
+    }
+}
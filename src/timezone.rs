@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2021 Normation SAS
+
+//! Time zones from the system IANA tz database
+//!
+//! Reads the compiled TZif files from `$TZDIR` or `/usr/share/zoneinfo` with `tz-rs`,
+//! and the `tzdata.zi` source when present to detect backward-compatibility aliases.
+
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Error};
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+fn zoneinfo() -> PathBuf {
+    env::var_os("TZDIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(ZONEINFO))
+}
+
+/// Path of the TZif file of a zone, if the name is well-formed
+fn zone_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let valid = !name.is_empty()
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c));
+    if valid {
+        Some(zoneinfo().join(path))
+    } else {
+        None
+    }
+}
+
+/// Whether the zone exists in the system database
+pub(crate) fn zone_exists(name: &str) -> bool {
+    zone_path(name)
+        .and_then(|p| fs::read(p).ok())
+        .map(|d| d.starts_with(b"TZif"))
+        .unwrap_or(false)
+}
+
+/// Backward-compatibility links of the system database, read once
+fn aliases() -> &'static HashSet<String> {
+    static ALIASES: OnceLock<HashSet<String>> = OnceLock::new();
+    ALIASES.get_or_init(|| {
+        fs::read_to_string(zoneinfo().join("tzdata.zi"))
+            .map(|source| {
+                source
+                    .lines()
+                    .filter_map(|l| {
+                        let mut fields = l.split_whitespace();
+                        match (fields.next(), fields.nth(1)) {
+                            (Some("L"), Some(alias)) => Some(alias.to_string()),
+                            _ => None,
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Whether the zone is a link kept for backward compatibility, like `US/Eastern`
+///
+/// Always `false` when the database does not include `tzdata.zi`.
+pub(crate) fn is_alias(name: &str) -> bool {
+    aliases().contains(name)
+}
+
+/// Time zone loaded from the system database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    name: String,
+    zone: tz::TimeZone,
+}
+
+impl TimeZone {
+    /// Load a zone by name, like `Europe/Paris`
+    pub fn load(name: &str) -> Result<Self, Error> {
+        let path = match zone_path(name) {
+            Some(p) => p,
+            None => bail!("Invalid time zone name '{}'", name),
+        };
+        let data = fs::read(&path).map_err(|e| Error::msg(format!("{}: {}", name, e)))?;
+        let zone = tz::TimeZone::from_tz_data(&data)
+            .map_err(|e| Error::msg(format!("Invalid time zone data for '{}': {}", name, e)))?;
+        Ok(Self {
+            name: name.to_string(),
+            zone,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Underlying `tz-rs` time zone
+    pub fn zone(&self) -> &tz::TimeZone {
+        &self.zone
+    }
+
+    /// Offset from UTC in seconds at the given time, positive east of Greenwich
+    pub fn offset_at(&self, time: SystemTime) -> i32 {
+        let timestamp = match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        self.zone
+            .find_local_time_type(timestamp)
+            .map(|t| t.ut_offset())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn it_loads_system_zones() {
+        if !Path::new(ZONEINFO).join("Europe/Paris").exists() {
+            return;
+        }
+        let paris = TimeZone::load("Europe/Paris").unwrap();
+        // 2021-07-01T00:00:00Z and 2100-01-01T00:00:00Z
+        let summer = UNIX_EPOCH + Duration::from_secs(1_625_097_600);
+        let far_winter = UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+        assert_eq!(paris.offset_at(summer), 7200);
+        assert_eq!(paris.offset_at(far_winter), 3600);
+        assert!(TimeZone::load("Europe/Nowhere").is_err());
+        assert!(TimeZone::load("../etc/passwd").is_err());
+    }
+}